@@ -1,4 +1,13 @@
 use clap::{Parser, Subcommand, builder::styling};
+use clap_verbosity_flag::Verbosity;
+use miette::Result;
+use tracing::debug;
+
+use crate::{
+    config::UserConfig,
+    controller::{Context, Controller, doctor},
+    error::ExitCode,
+};
 
 const STYLES: styling::Styles = styling::Styles::styled()
     .header(styling::AnsiColor::Green.on_default().bold())
@@ -17,28 +26,80 @@ pub struct Cli {
 
     #[command(flatten)]
     pub verbosity: clap_verbosity_flag::Verbosity,
+
+    #[command(flatten)]
+    pub global: GlobalOptions,
+}
+
+/// Options shared by every command
+#[derive(clap::Args, Clone, Default)]
+pub struct GlobalOptions {
+    /// Docker daemon to connect to, e.g. tcp://build-vm:2376. Overrides DOCKER_HOST and docker contexts
+    #[arg(long, global = true)]
+    pub docker_host: Option<String>,
+
+    /// Print results as text for humans or as JSON for scripts
+    #[arg(long, global = true, value_enum, default_value = "text")]
+    pub output: OutputFormat,
+
+    /// Answer yes to confirmations of destructive commands
+    #[arg(short, long, global = true)]
+    pub yes: bool,
+
+    /// Never prompt, refuse destructive commands without --force. Implied without a terminal
+    #[arg(
+        long,
+        global = true,
+        env = "PGD_NONINTERACTIVE",
+        value_parser = clap::builder::BoolishValueParser::new()
+    )]
+    pub non_interactive: bool,
+}
+
+#[derive(Clone, Copy, Default, PartialEq, Eq, clap::ValueEnum)]
+pub enum OutputFormat {
+    #[default]
+    Text,
+    /// One JSON document per line on stdout, errors as JSON on stderr
+    Json,
 }
 
-#[derive(Clone, clap::ValueEnum)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, clap::ValueEnum, serde::Deserialize)]
+#[serde(rename_all = "lowercase")]
 pub enum ConnectionFormat {
     /// DSN Url
     Dsn,
     // Human readable format
     Human,
+    /// DSN Url through the mounted socket directory
+    Socket,
 }
 
 #[derive(Subcommand)]
 pub enum ControlCommands {
     /// Start postgres instance
-    Start,
+    Start {
+        /// Print what would be done, without doing it
+        #[arg(long)]
+        dry_run: bool,
+    },
     /// Stop postgres instance
     Stop,
     /// Restart postgres instance
     Restart,
     /// (WARNING!) Destroy postgres instance
-    Destroy { force: bool },
+    Destroy {
+        #[arg(short, long)]
+        force: bool,
+        /// Keep the data volume, so the next instance of this project reuses it
+        #[arg(long)]
+        keep_data: bool,
+    },
     /// (WARNING!) Destruct database
-    Wipe { force: bool },
+    Wipe {
+        #[arg(short, long)]
+        force: bool,
+    },
 
     /// Status of instance
     Status,
@@ -47,10 +108,13 @@ pub enum ControlCommands {
         #[arg(short, long, default_value = "false")]
         follow: bool,
     },
+    /// (Sensitive) generate a new password and apply it to the instance
+    RotatePassword,
     /// (Sensitive) get connection details
     Conn {
-        #[arg(short, long, default_value = "dsn")]
-        format: ConnectionFormat,
+        /// Defaults to `connection_format` of the user config, or dsn
+        #[arg(short, long)]
+        format: Option<ConnectionFormat>,
     },
 }
 
@@ -59,6 +123,26 @@ pub enum Commands {
     /// Create a new project, or initialize instance for existing one
     Init,
 
+    /// Show what `pgd instance start` would do for the current project
+    Plan,
+
+    /// Diagnose the setup: daemon, disk space, ports, state and pgd.toml
+    Doctor {
+        /// Restore instances missing from state from the labels of their containers
+        #[arg(long)]
+        repair: bool,
+    },
+
+    /// Run a command against a throwaway instance that is removed afterwards
+    Ephemeral {
+        /// PostgreSQL version, either major (16) or exact (16.11). Defaults to `default_version` of the user config, or the latest
+        #[arg(long)]
+        version: Option<String>,
+        /// Command to run, DATABASE_URL points to the instance
+        #[arg(last = true, required = true)]
+        cmd: Vec<String>,
+    },
+
     /// Start the PostgreSQL container for the current project
     Instance {
         // Name of the instance you want to control. Defaults to current project
@@ -67,3 +151,132 @@ pub enum Commands {
         cmd: ControlCommands,
     },
 }
+
+/// What `pgd` runs, exiting with the code of a failure
+pub(crate) async fn main() -> Result<()> {
+    let cli = Cli::parse();
+    init_tracing(cli.verbosity);
+
+    // an invalid config is reported by the command itself
+    if let Ok(user) = UserConfig::load() {
+        user.color.apply();
+    }
+
+    let json = cli.global.output == OutputFormat::Json;
+    if json {
+        miette::set_hook(Box::new(|_| Box::new(miette::JSONReportHandler::new())))?;
+    }
+
+    if let Err(err) = run(cli).await {
+        // without the `Error: ` prefix in JSON mode, so stderr stays parseable
+        if json {
+            eprintln!("{err:?}");
+        } else {
+            eprintln!("Error: {err:?}");
+        }
+        std::process::exit(ExitCode::of(&err).into());
+    }
+
+    Ok(())
+}
+
+async fn run(cli: Cli) -> Result<()> {
+    debug!("pgd.start");
+
+    macro_rules! do_cmd {
+        ($name:expr, $method:ident $(, $arg:expr)*) => {{
+            let ctx = Context::new($name, &cli.global).await?;
+            Controller::new(ctx).$method($($arg),*).await?;
+        }};
+    }
+
+    match cli.command {
+        Commands::Init => {
+            do_cmd!(None, init_project);
+        }
+        Commands::Plan => {
+            do_cmd!(None, plan);
+        }
+        Commands::Doctor { repair } => {
+            // reports an unreachable daemon as a failed check, instead of failing outright
+            doctor::run(&cli.global, repair).await?;
+        }
+        Commands::Ephemeral { version, cmd } => {
+            // a broken pgd.toml or state file doesn't matter to a throwaway database
+            let ctx = Context::detached(&cli.global).await?;
+            let code = Controller::new(ctx).ephemeral(version, cmd).await?;
+            std::process::exit(code);
+        }
+        Commands::Instance { name, cmd } => match cmd {
+            ControlCommands::Start { dry_run } => do_cmd!(name, start, dry_run),
+            ControlCommands::Stop => do_cmd!(name, stop),
+            ControlCommands::Restart => do_cmd!(name, restart),
+            ControlCommands::Destroy { force, keep_data } => {
+                do_cmd!(name, destroy, force, keep_data)
+            }
+            ControlCommands::Logs { follow } => do_cmd!(name, logs, follow),
+            ControlCommands::Status => do_cmd!(name, status),
+            // can't override an instance for this command, because password is in config
+            ControlCommands::Conn { format } => do_cmd!(None, show_connection, format),
+            ControlCommands::Wipe { force } => do_cmd!(name, wipe, force),
+            // password lives in config, same as for `conn`
+            ControlCommands::RotatePassword => do_cmd!(None, rotate_password),
+        },
+    }
+
+    Ok(())
+}
+
+fn init_tracing(verbosity: Verbosity) {
+    tracing_subscriber::fmt()
+        .with_max_level(verbosity)
+        .without_time()
+        .with_target(false)
+        .with_writer(std::io::stderr)
+        .init();
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_cli_definition() {
+        use clap::CommandFactory;
+        Cli::command().debug_assert();
+    }
+
+    #[test]
+    fn test_non_interactive_from_env() {
+        use clap::{ArgAction, CommandFactory};
+
+        let flag = Cli::try_parse_from(["pgd", "--non-interactive", "plan"]).unwrap();
+        let command = Cli::command();
+        let arg = command
+            .get_arguments()
+            .find(|arg| arg.get_id() == "non_interactive")
+            .unwrap();
+        // the variable goes through the same parser as a value would, without
+        // setting it for the other tests of this process
+        let with_value =
+            Cli::command().mut_arg("non_interactive", |arg| arg.action(ArgAction::Set));
+        let parse = |value: &str| {
+            with_value
+                .clone()
+                .try_get_matches_from(["pgd", "--non-interactive", value, "plan"])
+                .unwrap()
+                .get_one::<bool>("non_interactive")
+                .copied()
+        };
+
+        assert!(flag.global.non_interactive);
+        assert_eq!(
+            arg.get_env(),
+            Some(std::ffi::OsStr::new("PGD_NONINTERACTIVE"))
+        );
+        for value in ["1", "yes", "on", "true"] {
+            assert_eq!(parse(value), Some(true), "{value}");
+        }
+        assert_eq!(parse("0"), Some(false));
+    }
+}
@@ -3,8 +3,11 @@ use miette::{Context, IntoDiagnostic, Result};
 use serde::{Deserialize, Serialize};
 use serde_with::{DisplayFromStr, serde_as};
 use std::fmt::Display;
+use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+
+use crate::cli::ConnectionFormat;
 use std::{
-    path::{Path, PathBuf},
+    path::{Component, Path, PathBuf},
     str::FromStr,
 };
 
@@ -35,8 +38,125 @@ impl Display for PostgresVersion {
     }
 }
 
+/// Container engine running the instances
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum RuntimeKind {
+    #[default]
+    Docker,
+    Podman,
+}
+
+impl FromStr for RuntimeKind {
+    type Err = miette::Report;
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "docker" => Ok(Self::Docker),
+            "podman" => Ok(Self::Podman),
+            other => Err(miette!(
+                help = "expected either `docker` or `podman`",
+                "unknown container runtime `{}`",
+                other
+            )),
+        }
+    }
+}
+
+impl Display for RuntimeKind {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            RuntimeKind::Docker => write!(f, "docker"),
+            RuntimeKind::Podman => write!(f, "podman"),
+        }
+    }
+}
+
+/// Amount of memory the way Docker takes it, e.g. `256m` or `1g`
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(transparent)]
+pub struct ByteSize(pub u64);
+
+impl FromStr for ByteSize {
+    type Err = miette::Report;
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        let lower = s.trim().to_ascii_lowercase();
+        let digits = lower.trim_end_matches(|c: char| c.is_ascii_alphabetic());
+        let multiplier: u64 = match &lower[digits.len()..] {
+            "" | "b" => 1,
+            "k" | "kb" | "kib" => 1 << 10,
+            "m" | "mb" | "mib" => 1 << 20,
+            "g" | "gb" | "gib" => 1 << 30,
+            unit => {
+                return Err(miette!(
+                    help = "expected a number with an optional k, m or g suffix, e.g. `256m`",
+                    "unknown unit `{}` in size `{}`",
+                    unit,
+                    s
+                ));
+            }
+        };
+        let value: u64 = digits.trim().parse().map_err(|_| {
+            miette!(
+                help = "expected a number with an optional k, m or g suffix, e.g. `256m`",
+                "invalid size `{}`",
+                s
+            )
+        })?;
+        value
+            .checked_mul(multiplier)
+            .map(Self)
+            .ok_or_else(|| miette!("size `{}` is too large", s))
+    }
+}
+
+impl Display for ByteSize {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let units = [(1 << 30, "g"), (1 << 20, "m"), (1 << 10, "k")];
+        match units
+            .iter()
+            .find(|(size, _)| self.0 >= *size && self.0.is_multiple_of(*size))
+        {
+            Some((size, unit)) => write!(f, "{}{}", self.0 / size, unit),
+            None => write!(f, "{}", self.0),
+        }
+    }
+}
+
+/// Resources the container of an instance may use, unlimited when unset
+#[derive(Debug, Clone, Copy, Default, PartialEq, Serialize, Deserialize)]
+pub struct Limits {
+    pub memory: Option<ByteSize>,
+    pub cpus: Option<f64>,
+    /// Size of `/dev/shm`, 64m by default
+    pub shm_size: Option<ByteSize>,
+}
+
+impl Display for Limits {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let mut parts = Vec::new();
+        if let Some(memory) = self.memory {
+            parts.push(format!("memory {memory}"));
+        }
+        if let Some(cpus) = self.cpus {
+            parts.push(format!("{cpus} cpus"));
+        }
+        if let Some(shm_size) = self.shm_size {
+            parts.push(format!("shm {shm_size}"));
+        }
+        if parts.is_empty() {
+            return write!(f, "no limits");
+        }
+        write!(f, "{}", parts.join(", "))
+    }
+}
+
 const PROJECT_FILENAME: &str = "pgd.toml";
 
+/// Address instances listen on unless `bind` says otherwise
+pub const LOOPBACK: IpAddr = IpAddr::V4(Ipv4Addr::LOCALHOST);
+
 /// Configuration stored in pgd.toml
 #[serde_as]
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -48,11 +168,84 @@ pub struct PGDConfig {
     /// Database password
     pub password: String,
 
-    /// Port to bind on host
-    pub port: u16,
+    /// Port to bind on host. Can be left out with `socket_dir`, to skip TCP entirely
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub port: Option<u16>,
+
+    /// Host address to listen on, 127.0.0.1 when unset. `0.0.0.0` or `::` expose
+    /// the instance to other machines on the network
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub bind: Option<IpAddr>,
+
+    /// Keep PGDATA in this directory, relative to the project, instead of a Docker volume
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub data_dir: Option<PathBuf>,
+
+    /// Mount the socket directory of postgres here, relative to the project
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub socket_dir: Option<PathBuf>,
+
+    /// Serve TLS with a certificate signed by the local CA of pgd
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub tls: bool,
+
+    /// Memory limit of the container, e.g. `1g`
+    #[serde_as(as = "Option<DisplayFromStr>")]
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub memory: Option<ByteSize>,
+
+    /// How many CPUs the container may use, fractions allowed
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub cpus: Option<f64>,
+
+    /// Size of `/dev/shm`, e.g. `256m`. Parallel queries on larger datasets outgrow the default 64m
+    #[serde_as(as = "Option<DisplayFromStr>")]
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub shm_size: Option<ByteSize>,
+
+    /// Container engine to use. `PGD_RUNTIME` takes precedence, detected when unset
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub runtime: Option<RuntimeKind>,
 }
 
 impl PGDConfig {
+    /// Only the settings pgd.toml requires, with password `secret`. Tests override
+    /// the rest with struct update syntax
+    #[cfg(test)]
+    pub fn for_test(version: &str, port: u16) -> Self {
+        Self {
+            version: version.parse().unwrap(),
+            password: "secret".to_string(),
+            port: Some(port),
+            bind: None,
+            data_dir: None,
+            socket_dir: None,
+            tls: false,
+            memory: None,
+            cpus: None,
+            shm_size: None,
+            runtime: None,
+        }
+    }
+
+    pub fn bind_address(&self) -> IpAddr {
+        self.bind.unwrap_or(LOOPBACK)
+    }
+
+    pub fn limits(&self) -> Limits {
+        Limits {
+            memory: self.memory,
+            cpus: self.cpus,
+            shm_size: self.shm_size,
+        }
+    }
+
+    /// Host address and port the container publishes postgres on, if any
+    pub fn address(&self) -> Option<SocketAddr> {
+        self.port
+            .map(|port| SocketAddr::new(self.bind_address(), port))
+    }
+
     pub fn load(path: impl AsRef<Path>) -> Result<Self> {
         let path = path.as_ref();
         let content = std::fs::read_to_string(path)
@@ -60,8 +253,38 @@ impl PGDConfig {
             .wrap_err_with(|| format!("Failed to read config file: {}", path.display()))?;
 
         let config: PGDConfig = toml::from_str(&content)
-            .into_diagnostic()
-            .wrap_err("Failed to parse pgd.toml")?;
+            .map_err(|err| crate::error::Error::config_invalid(path, content.clone(), err))?;
+
+        if let Some(dir) = &config.data_dir
+            && !is_inside_project(dir)
+        {
+            miette::bail!(
+                help = "Set data_dir to a directory inside the project, e.g. `data_dir = \".pgd/data\"`",
+                "{} sets data_dir to {}, which is not a directory inside the project",
+                path.display(),
+                dir.display()
+            );
+        }
+
+        if config
+            .cpus
+            .is_some_and(|cpus| !cpus.is_finite() || cpus <= 0.0)
+        {
+            miette::bail!(
+                help = "Set cpus to a positive number, e.g. `cpus = 2` or `cpus = 0.5`",
+                "{} sets cpus to {}",
+                path.display(),
+                config.cpus.unwrap_or_default()
+            );
+        }
+
+        if config.port.is_none() && config.socket_dir.is_none() {
+            miette::bail!(
+                help = "Set port, or socket_dir to connect through a unix socket only",
+                "{} sets neither port nor socket_dir",
+                path.display()
+            );
+        }
 
         Ok(config)
     }
@@ -80,6 +303,75 @@ impl PGDConfig {
     }
 }
 
+/// Relative, below the project root and never climbing out of it, since `pgd instance destroy`
+/// removes the directory
+fn is_inside_project(dir: &Path) -> bool {
+    let mut depth = 0;
+    for component in dir.components() {
+        match component {
+            Component::Normal(_) => depth += 1,
+            Component::CurDir => {}
+            Component::ParentDir | Component::RootDir | Component::Prefix(_) => return false,
+        }
+    }
+    depth > 0
+}
+
+/// Where PGDATA of an instance lives on the host
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum DataStorage {
+    /// Named Docker volume
+    Volume(String),
+    /// Host directory bind-mounted into the container
+    HostDir(PathBuf),
+}
+
+impl Display for DataStorage {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            DataStorage::Volume(name) => write!(f, "volume {name}"),
+            DataStorage::HostDir(path) => write!(f, "directory {}", path.display()),
+        }
+    }
+}
+
+impl DataStorage {
+    /// Whether PGDATA recorded here is where `configured` asks for it. Without `data_dir`
+    /// any volume will do, as instances of older releases keep it in an anonymous one
+    pub fn satisfies(&self, configured: &DataStorage) -> bool {
+        match (self, configured) {
+            (DataStorage::Volume(_), DataStorage::Volume(_)) => true,
+            (recorded, configured) => recorded == configured,
+        }
+    }
+}
+
+/// How postgres of an instance is reached from the host
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Listen {
+    /// Published TCP port and the host address it listens on
+    pub tcp: Option<SocketAddr>,
+    /// Host directory the socket directory of postgres is mounted to
+    pub socket_dir: Option<PathBuf>,
+    /// Host directory holding the server certificate and key, when serving TLS
+    pub tls: Option<PathBuf>,
+}
+
+impl Display for Listen {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match (&self.tcp, &self.socket_dir) {
+            (Some(tcp), Some(dir)) => write!(f, "{tcp} and socket in {}", dir.display())?,
+            (Some(tcp), None) => write!(f, "{tcp}")?,
+            (None, Some(dir)) => write!(f, "socket in {}", dir.display())?,
+            (None, None) => write!(f, "nothing")?,
+        }
+        if self.tls.is_some() {
+            write!(f, " with TLS")?;
+        }
+        Ok(())
+    }
+}
+
 #[derive(Debug, Clone)]
 pub struct Project {
     /// Project name (derived from directory name)
@@ -101,9 +393,48 @@ impl Project {
         container_name
     }
 
+    /// Name of the volume holding PGDATA; unlike the container, it survives recreation
+    pub fn volume_name(&self) -> String {
+        format!("pgd-{}-data", self.name)
+    }
+
+    /// Storage configured for PGDATA, either `data_dir` or the project volume
+    pub fn data_storage(&self) -> DataStorage {
+        match &self.config.data_dir {
+            Some(dir) => DataStorage::HostDir(self.path.join(dir)),
+            None => DataStorage::Volume(self.volume_name()),
+        }
+    }
+
+    /// Host directory the socket of postgres shows up in, with `socket_dir`
+    pub fn socket_path(&self) -> Option<PathBuf> {
+        self.config
+            .socket_dir
+            .as_ref()
+            .map(|dir| self.path.join(dir))
+    }
+
+    /// Without the certificate directory, which belongs to the local CA. See
+    /// [`Context::listen`](crate::controller::Context::listen)
+    pub fn listen(&self) -> Listen {
+        Listen {
+            tcp: self.config.address(),
+            socket_dir: self.socket_path(),
+            tls: None,
+        }
+    }
+
     /// Load a project from the current directory
     pub fn load() -> Result<Option<Self>> {
-        let project_path = get_project_path()?;
+        Self::load_from(&get_project_path()?)
+    }
+
+    /// Load a project from the directory containing its pgd.toml
+    pub fn load_from(path: &Path) -> Result<Option<Self>> {
+        let project_path = path
+            .canonicalize()
+            .into_diagnostic()
+            .wrap_err_with(|| format!("Failed to resolve project path: {}", path.display()))?;
         let config_path = project_path.join(PROJECT_FILENAME);
 
         if !config_path.exists() {
@@ -120,6 +451,11 @@ impl Project {
         }))
     }
 
+    /// Name a project created in the current directory gets
+    pub fn current_name() -> Result<String> {
+        Self::extract_project_name(&get_project_path()?)
+    }
+
     pub fn new(config: PGDConfig) -> Result<Self> {
         let project_path = get_project_path()?;
         let name = Self::extract_project_name(&project_path)?;
@@ -154,6 +490,87 @@ impl Project {
     }
 }
 
+/// User-level defaults from `~/.config/pgd/config.toml`, every setting is optional
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct UserConfig {
+    /// Version of new projects and ephemeral instances, major (`16`) or exact (`16.11`)
+    pub default_version: Option<String>,
+
+    /// First port new projects are given, 5432 when unset
+    pub port_range_start: Option<u16>,
+
+    /// Last port new projects are given, 99 ports after the start when unset
+    pub port_range_end: Option<u16>,
+
+    /// Registry to pull postgres images through instead of Docker Hub, e.g. `mirror.gcr.io`
+    pub registry_mirror: Option<String>,
+
+    /// Format of `pgd instance conn` without `--format`
+    pub connection_format: Option<ConnectionFormat>,
+
+    /// What to do with other running instances when one is started
+    pub auto_stop: AutoStop,
+
+    /// Whether to color text output
+    pub color: ColorChoice,
+}
+
+impl UserConfig {
+    /// Defaults when the file doesn't exist
+    pub fn load() -> Result<Self> {
+        Self::load_from(&crate::paths::config_file()?)
+    }
+
+    pub fn load_from(path: &Path) -> Result<Self> {
+        let content = match std::fs::read_to_string(path) {
+            Ok(content) => content,
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => return Ok(Self::default()),
+            Err(err) => {
+                return Err(err)
+                    .into_diagnostic()
+                    .wrap_err_with(|| format!("Failed to read config file: {}", path.display()));
+            }
+        };
+
+        let config = toml::from_str(&content)
+            .map_err(|err| crate::error::Error::user_config_invalid(path, content.clone(), err))?;
+
+        Ok(config)
+    }
+}
+
+/// Other instances to stop when starting one
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum AutoStop {
+    /// Leave them running
+    #[default]
+    Never,
+    /// Stop every other running instance, so only one runs at a time
+    Others,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ColorChoice {
+    /// Color when writing to a terminal, unless `NO_COLOR` is set
+    #[default]
+    Auto,
+    Always,
+    Never,
+}
+
+impl ColorChoice {
+    pub fn apply(self) {
+        match self {
+            ColorChoice::Auto => colored::control::unset_override(),
+            ColorChoice::Always => colored::control::set_override(true),
+            ColorChoice::Never => colored::control::set_override(false),
+        }
+    }
+}
+
 fn get_project_path() -> Result<PathBuf, miette::Error> {
     let project_path = std::env::current_dir()
         .into_diagnostic()
@@ -171,4 +588,89 @@ mod tests {
         let name = Project::extract_project_name(&path).unwrap();
         assert_eq!(name, "my-project");
     }
+
+    #[test]
+    fn test_runtime_kind_from_config() {
+        let config: PGDConfig = toml::from_str(
+            "version = \"18.1\"\npassword = \"x\"\nport = 5432\nruntime = \"podman\"",
+        )
+        .unwrap();
+        assert_eq!(config.runtime, Some(RuntimeKind::Podman));
+        assert_eq!(
+            "Docker".parse::<RuntimeKind>().unwrap(),
+            RuntimeKind::Docker
+        );
+        assert!("containerd".parse::<RuntimeKind>().is_err());
+    }
+
+    #[test]
+    fn test_rejects_data_dir_outside_project() {
+        let dir = std::env::temp_dir().join(format!("pgd-data-dir-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("pgd.toml");
+        let load = |data_dir: &str| {
+            std::fs::write(
+                &path,
+                format!(
+                    "version = \"18.1\"\npassword = \"x\"\nport = 5432\ndata_dir = {data_dir:?}"
+                ),
+            )
+            .unwrap();
+            PGDConfig::load(&path)
+        };
+
+        for data_dir in [".", "./", "..", "../other", "data/../..", "/home/me"] {
+            assert!(load(data_dir).is_err(), "{data_dir} was accepted");
+        }
+        for data_dir in [".pgd/data", "./data", "data/pg"] {
+            assert!(load(data_dir).is_ok(), "{data_dir} was rejected");
+        }
+
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn test_limits_from_config() {
+        let config: PGDConfig = toml::from_str(
+            "version = \"18.1\"\npassword = \"x\"\nport = 5432\nmemory = \"1g\"\ncpus = 2\nshm_size = \"256M\"",
+        )
+        .unwrap();
+        assert_eq!(config.memory, Some(ByteSize(1 << 30)));
+        assert_eq!(config.cpus, Some(2.0));
+        assert_eq!(config.shm_size.unwrap().to_string(), "256m");
+        assert_eq!(config.limits().to_string(), "memory 1g, 2 cpus, shm 256m");
+
+        assert_eq!("1536k".parse::<ByteSize>().unwrap().to_string(), "1536k");
+        assert_eq!("100".parse::<ByteSize>().unwrap(), ByteSize(100));
+        assert!("1t".parse::<ByteSize>().is_err());
+        assert!("m".parse::<ByteSize>().is_err());
+    }
+
+    #[test]
+    fn test_user_config() {
+        let config: UserConfig = toml::from_str(
+            "default_version = \"16\"\nport_range_start = 6000\nport_range_end = 6009\nregistry_mirror = \"mirror.gcr.io\"\n\
+             connection_format = \"human\"\nauto_stop = \"others\"\ncolor = \"never\"",
+        )
+        .unwrap();
+        assert_eq!(config.default_version.as_deref(), Some("16"));
+        assert_eq!(config.port_range_start, Some(6000));
+        assert_eq!(config.port_range_end, Some(6009));
+        assert_eq!(config.registry_mirror.as_deref(), Some("mirror.gcr.io"));
+        assert_eq!(config.connection_format, Some(ConnectionFormat::Human));
+        assert_eq!(config.auto_stop, AutoStop::Others);
+        assert_eq!(config.color, ColorChoice::Never);
+
+        let empty: UserConfig = toml::from_str("").unwrap();
+        assert_eq!(empty.auto_stop, AutoStop::Never);
+        assert!(toml::from_str::<UserConfig>("port_range = 6000").is_err());
+
+        let missing = std::env::temp_dir().join("pgd-no-such-config.toml");
+        assert!(
+            UserConfig::load_from(&missing)
+                .unwrap()
+                .port_range_start
+                .is_none()
+        );
+    }
 }
@@ -3,43 +3,143 @@ use miette::miette;
 use colored::Colorize;
 use comfy_table::{Attribute, Cell, Color, ContentArrangement, Table, presets::UTF8_FULL};
 use futures::TryStreamExt;
-use miette::{IntoDiagnostic, Result};
+use miette::{Context as _, IntoDiagnostic, Result};
+use std::{
+    fmt::Display,
+    net::IpAddr,
+    path::{Path, PathBuf},
+    sync::Arc,
+};
+use tracing::info;
 
 use crate::{
-    cli::ConnectionFormat,
-    config::{PGDConfig, Project},
+    cli::{ConnectionFormat, GlobalOptions, OutputFormat},
+    config::{AutoStop, ByteSize, DataStorage, Listen, PGDConfig, Project, UserConfig},
     consts::{DATABASE, USERNAME},
-    controller::{docker::DockerController, reconciler::Reconciler},
+    controller::{
+        docker::DockerController,
+        ephemeral::EphemeralInstance,
+        output::{
+            ActionReport, ConnectionInfo, Drift, DriftField, InitReport, InstanceStatus, LogLine,
+            Outcome, PlanReport, StatusReport, print_json,
+        },
+        ports::PortAllocator,
+        reconciler::Reconciler,
+        runtime::ContainerRuntime,
+        tls::LocalCa,
+    },
+    error::Error,
     state::{InstanceState, StateManager},
 };
 
-mod docker;
-mod utils;
+pub(crate) mod crash;
+pub(crate) mod docker;
+pub(crate) mod doctor;
+pub(crate) mod ephemeral;
+pub(crate) mod output;
+pub(crate) mod ports;
+pub(crate) mod reconciler;
+pub(crate) mod runtime;
+pub(crate) mod tls;
+
+pub(crate) mod utils;
 
-pub mod reconciler;
+/// Receives the progress messages of long-running operations
+pub type ProgressFn = Arc<dyn Fn(&str) + Send + Sync>;
 
 pub struct Context {
-    docker: DockerController,
+    runtime: Arc<dyn ContainerRuntime>,
     project: Option<Project>,
     instance: Option<InstanceState>,
     state: StateManager,
+    options: GlobalOptions,
+    user: UserConfig,
+    /// Overrides the CA under the state directory, e.g. in tests
+    ca: Option<LocalCa>,
+    /// Logged at info level when unset, the CLI prints them
+    progress: Option<ProgressFn>,
 }
 
 impl Context {
     pub fn require_instance(&self) -> Result<&InstanceState> {
-        self.instance.as_ref().ok_or(miette!("This command requires instance. Either initiliaze a project, or pass -I with instance name"))
+        let instance = self.instance.as_ref().ok_or(Error::InstanceRequired)?;
+        self.ensure_same_daemon(instance)?;
+        Ok(instance)
+    }
+
+    /// Containers are only reachable through the daemon they were created on
+    pub(crate) fn ensure_same_daemon(&self, instance: &InstanceState) -> Result<()> {
+        let current = self.runtime.daemon_host();
+        match &instance.daemon_host {
+            Some(host) if *host != current => miette::bail!(
+                help = format!("Pass `--docker-host {host}` or switch back to its docker context"),
+                "Instance was created on {}, but pgd is connected to {}",
+                host,
+                current
+            ),
+            _ => Ok(()),
+        }
     }
 
     pub fn require_project(&self) -> Result<&Project> {
-        self.project.as_ref().ok_or(miette!(
-            "This command requires project. Please, initiliaze a project."
-        ))
+        Ok(self.project.as_ref().ok_or(Error::ProjectRequired)?)
+    }
+
+    pub fn project(&self) -> Option<&Project> {
+        self.project.as_ref()
+    }
+
+    pub fn runtime(&self) -> &Arc<dyn ContainerRuntime> {
+        &self.runtime
     }
 
-    pub async fn new(instance_override: Option<String>) -> Result<Self> {
-        let project = Project::load()?;
+    /// Context for the project in the current directory, printing progress to stdout like the CLI
+    pub async fn new(instance_override: Option<String>, options: &GlobalOptions) -> Result<Self> {
+        let ctx = Self::with_project(Project::load()?, instance_override, options.clone()).await?;
+        Ok(ctx.with_progress(Arc::new(|message| println!("{message}"))))
+    }
+
+    /// Context for the project at `path`
+    pub async fn at(path: &Path) -> Result<Self> {
+        Self::with_project(Project::load_from(path)?, None, GlobalOptions::default()).await
+    }
+
+    /// Context without a project and with in-memory state, for ephemeral instances,
+    /// which need neither a valid pgd.toml nor a readable state file
+    pub async fn detached(options: &GlobalOptions) -> Result<Self> {
+        let user = UserConfig::load()?;
+        let runtime = connect(None, options, &user).await?;
+
+        Ok(
+            Self::with_runtime(Arc::new(runtime), None, None, StateManager::in_memory())
+                .with_options(options.clone())
+                .with_user_config(user),
+        )
+    }
+
+    async fn with_project(
+        project: Option<Project>,
+        instance_override: Option<String>,
+        options: GlobalOptions,
+    ) -> Result<Self> {
         let state = StateManager::new()?;
+        let user = UserConfig::load()?;
+        let runtime = connect(project.as_ref(), &options, &user).await?;
+
+        Ok(
+            Self::with_runtime(Arc::new(runtime), project, instance_override, state)
+                .with_options(options)
+                .with_user_config(user),
+        )
+    }
 
+    /// Context on top of an arbitrary runtime and state, e.g. the in-memory fakes in tests
+    pub fn with_runtime(
+        runtime: Arc<dyn ContainerRuntime>,
+        project: Option<Project>,
+        instance_override: Option<String>,
+        state: StateManager,
+    ) -> Self {
         let instance = match (project.as_ref(), instance_override) {
             (None, None) => None,
             // prioritizing provided instance name
@@ -47,15 +147,109 @@ impl Context {
             (Some(project), None) => state.get(&project.name),
         };
 
-        Ok(Self {
-            docker: DockerController::new().await?,
+        Self {
+            runtime,
             project,
             instance,
             state,
-        })
+            options: GlobalOptions::default(),
+            user: UserConfig::default(),
+            ca: None,
+            progress: None,
+        }
+    }
+
+    pub fn with_options(mut self, options: GlobalOptions) -> Self {
+        self.options = options;
+        self
+    }
+
+    pub fn with_user_config(mut self, user: UserConfig) -> Self {
+        self.user = user;
+        self
+    }
+
+    pub fn user_config(&self) -> &UserConfig {
+        &self.user
+    }
+
+    pub fn with_progress(mut self, progress: ProgressFn) -> Self {
+        self.progress = Some(progress);
+        self
+    }
+
+    pub fn with_ca(mut self, ca: LocalCa) -> Self {
+        self.ca = Some(ca);
+        self
+    }
+
+    pub fn ca(&self) -> Result<LocalCa> {
+        match &self.ca {
+            Some(ca) => Ok(ca.clone()),
+            None => LocalCa::open(),
+        }
+    }
+
+    /// Where the instance of a project listens, with the certificate directory when it serves TLS
+    pub fn listen(&self, project: &Project) -> Result<Listen> {
+        let mut listen = project.listen();
+        if project.config.tls {
+            listen.tls = Some(self.ca()?.server_dir(&project.name));
+        }
+        Ok(listen)
+    }
+
+    /// Certificate clients verify the instance of a project against, when it serves TLS
+    pub fn root_cert(&self, project: &Project) -> Result<Option<PathBuf>> {
+        if !project.config.tls {
+            return Ok(None);
+        }
+        Ok(Some(self.ca()?.root_cert()))
+    }
+
+    pub fn state(&self) -> &StateManager {
+        &self.state
+    }
+
+    pub fn options(&self) -> &GlobalOptions {
+        &self.options
+    }
+
+    /// Prompts need someone at a terminal to answer them
+    pub(crate) fn interactive(&self) -> bool {
+        use std::io::IsTerminal;
+
+        !self.options.non_interactive && std::io::stdin().is_terminal()
+    }
+
+    pub(crate) fn json(&self) -> bool {
+        self.options.output == OutputFormat::Json
+    }
+
+    /// Progress messages are for humans only, so JSON output stays parseable
+    pub(crate) fn progress(&self, message: impl Display) {
+        if self.json() {
+            return;
+        }
+        match &self.progress {
+            Some(progress) => progress(&message.to_string()),
+            None => info!("{message}"),
+        }
     }
 }
 
+/// Runtime of the project, or the default one, pulling through the mirror of the user config
+async fn connect(
+    project: Option<&Project>,
+    options: &GlobalOptions,
+    user: &UserConfig,
+) -> Result<DockerController> {
+    let kind = docker::resolve_runtime(project.and_then(|p| p.config.runtime))?;
+    Ok(DockerController::new(kind, options.docker_host.as_deref())
+        .await?
+        .with_registry_mirror(user.registry_mirror.clone()))
+}
+
 /// Main CLI command dispatcher
 pub struct Controller {
     ctx: Context,
@@ -70,52 +264,164 @@ impl Controller {
 
         let mut logs = self
             .ctx
-            .docker
-            .stream_logs(&instance.container_id, follow)
-            .await;
+            .runtime
+            .stream_logs(&instance.container_id, follow, None);
 
-        while let Some(log) = logs.try_next().await? {
-            let bytes = log.into_bytes();
-            let line = String::from_utf8_lossy(bytes.as_ref());
-            print!("{line}");
+        while let Some(chunk) = logs.try_next().await? {
+            if self.ctx.json() {
+                for line in chunk.lines() {
+                    print_json(&LogLine { line })?;
+                }
+            } else {
+                print!("{chunk}");
+            }
         }
 
         Ok(())
     }
 
-    pub async fn show_connection(&self, format: ConnectionFormat) -> Result<()> {
+    pub async fn show_connection(&self, format: Option<ConnectionFormat>) -> Result<()> {
         let project = self.ctx.require_project()?;
         let reconciler = Reconciler { ctx: &self.ctx };
 
         reconciler.reconcile(project).await?;
 
+        let host = ports::connect_host(project.config.bind_address());
+        let socket_dir = project.socket_path();
+        let root_cert = self.ctx.root_cert(project)?;
+        let tcp_dsn = project.config.port.map(|port| {
+            let dsn = utils::format_dsn(host, &project.config.password, port);
+            match &root_cert {
+                Some(root_cert) => utils::with_tls(dsn, root_cert),
+                None => dsn,
+            }
+        });
+        let socket_dsn = socket_dir
+            .as_deref()
+            .map(|dir| utils::format_socket_dsn(dir, &project.config.password));
+        // pgd.toml has at least one of them
+        let dsn = tcp_dsn.or_else(|| socket_dsn.clone()).unwrap_or_default();
+
+        if self.ctx.json() {
+            return print_json(&ConnectionInfo {
+                project: project.name.clone(),
+                postgres_version: project.config.version.to_string(),
+                host: project.config.port.map(|_| host.to_string()),
+                port: project.config.port,
+                socket_dir,
+                root_cert,
+                username: USERNAME,
+                password: project.config.password.clone(),
+                database: DATABASE,
+                dsn,
+                socket_dsn,
+            });
+        }
+
+        let format = format
+            .or(self.ctx.user.connection_format)
+            .unwrap_or(ConnectionFormat::Dsn);
+
         match format {
             ConnectionFormat::Dsn => {
-                println!(
-                    "postgres://{}:{}@127.0.0.1:{}/{}",
-                    USERNAME, project.config.password, project.config.port, DATABASE
-                );
+                println!("{dsn}");
             }
             ConnectionFormat::Human => {
-                format_conn_human(project);
+                format_conn_human(project, host, root_cert.as_deref());
+            }
+            ConnectionFormat::Socket => {
+                let Some(socket_dsn) = socket_dsn else {
+                    miette::bail!(
+                        help = "Set socket_dir in pgd.toml, e.g. socket_dir = \".pgd/socket\"",
+                        "{} does not mount its socket directory",
+                        project.name
+                    );
+                };
+                println!("{socket_dsn}");
             }
         }
 
         Ok(())
     }
 
-    pub async fn start(&self) -> Result<()> {
+    pub async fn start(&self, dry_run: bool) -> Result<()> {
+        if dry_run {
+            return self.plan().await;
+        }
+
         let project = self.ctx.require_project()?;
         let reconciler = Reconciler { ctx: &self.ctx };
 
-        println!("{}", "Starting container...".cyan());
+        if self.ctx.user.auto_stop == AutoStop::Others {
+            self.stop_others(&project.name).await?;
+        }
+
+        self.ctx.progress("Starting container...".cyan());
         reconciler.reconcile(project).await?;
+        warn_if_exposed(project);
+
+        self.report("start", project, Outcome::Done, "Container started")
+    }
+
+    /// Stops the running instances of other projects on this daemon, for `auto_stop = "others"`
+    async fn stop_others(&self, current: &str) -> Result<()> {
+        for (name, instance) in self.ctx.state.instances() {
+            if name == current || self.ctx.ensure_same_daemon(&instance).is_err() {
+                continue;
+            }
+            // containers removed behind pgd's back have nothing to stop
+            if !self
+                .ctx
+                .runtime
+                .is_container_running_by_id(&instance.container_id)
+                .await
+                .unwrap_or(false)
+            {
+                continue;
+            }
+
+            self.ctx
+                .progress(format!("Stopping {name} (auto_stop = \"others\")...").cyan());
+            self.ctx
+                .runtime
+                .stop_container(&instance.container_id, 10)
+                .await?;
+        }
+
+        Ok(())
+    }
+
+    /// Print what `start` would do, without doing it
+    pub async fn plan(&self) -> Result<()> {
+        let project = self.ctx.require_project()?;
+        let actions = Reconciler { ctx: &self.ctx }.plan(project).await?;
+
+        if self.ctx.json() {
+            return print_json(&PlanReport {
+                project: project.name.clone(),
+                container_name: project.container_name(),
+                actions,
+            });
+        }
+
+        if actions.is_empty() {
+            println!(
+                "{} {} {}",
+                "✓".green().bold(),
+                "Nothing to do, up to date:".green(),
+                project.container_name().yellow()
+            );
+            return Ok(());
+        }
+
         println!(
-            "{} {} {}",
-            "✓".green().bold(),
-            "Container started".green(),
+            "{} {}",
+            "Plan for".cyan(),
             project.container_name().yellow()
         );
+        for (i, action) in actions.iter().enumerate() {
+            println!("  {}. {}", i + 1, action);
+        }
 
         Ok(())
     }
@@ -126,27 +432,21 @@ impl Controller {
 
         if !self
             .ctx
-            .docker
+            .runtime
             .is_container_running_by_id(&instance.container_id)
             .await?
         {
-            println!("{}", "Container is not running".yellow());
-            return Ok(());
+            self.ctx.progress("Container is not running".yellow());
+            return self.report("stop", project, Outcome::Unchanged, "");
         }
 
-        println!("{}", "Stopping container...".cyan());
+        self.ctx.progress("Stopping container...".cyan());
         self.ctx
-            .docker
+            .runtime
             .stop_container(&instance.container_id, 10)
             .await?;
-        println!(
-            "{} {} {}",
-            "✓".green().bold(),
-            "Stopped container".green(),
-            project.container_name().yellow()
-        );
 
-        Ok(())
+        self.report("stop", project, Outcome::Done, "Stopped container")
     }
 
     pub async fn restart(&self) -> Result<()> {
@@ -154,237 +454,328 @@ impl Controller {
         let project = self.ctx.require_project()?;
         let reconciler = Reconciler { ctx: &self.ctx };
 
-        println!("{}", "Restarting container...".cyan());
+        self.ctx.progress("Restarting container...".cyan());
 
         // Stop container first if it's running, otherwise reconciler won't do anything
         if self
             .ctx
-            .docker
+            .runtime
             .is_container_running_by_id(&instance.container_id)
             .await?
         {
             self.ctx
-                .docker
+                .runtime
                 .stop_container(&instance.container_id, 10)
                 .await?;
         }
 
         reconciler.reconcile(project).await?;
-        println!(
-            "{} {} {}",
-            "✓".green().bold(),
-            "Restarted container".green(),
-            project.container_name().yellow()
-        );
 
-        Ok(())
+        self.report("restart", project, Outcome::Done, "Restarted container")
     }
 
-    pub async fn destroy(&self, force: bool) -> Result<()> {
+    pub async fn destroy(&self, force: bool, keep_data: bool) -> Result<()> {
         let instance = self.ctx.require_instance()?;
         let project = self.ctx.require_project()?;
 
-        if !force {
-            use cliclack::{confirm, outro};
-            let confirmed = confirm(
-                format!(
-                    "Are you sure you want to destroy container '{}'? This will remove the container and all its volumes.",
-                    project.container_name()
-                ),
-            )
-            .interact()
-            .into_diagnostic()?;
+        // volumes of older instances aren't the one the next instance mounts, so keeping
+        // them would leave the data behind where nothing picks it up
+        if keep_data && instance.storage() != Some(project.data_storage()) {
+            let volume = match instance.storage() {
+                Some(storage) => Some(storage.to_string()),
+                None => self
+                    .ctx
+                    .runtime
+                    .get_container_data_volume(&instance.container_id, &instance.postgres_version)
+                    .await?
+                    .map(|volume| format!("volume {volume}")),
+            };
+            miette::bail!(
+                help = "Copy the data out of it first, or destroy without --keep-data",
+                "{} keeps its data in {}, which the next instance would not reuse",
+                project.container_name(),
+                volume.as_deref().unwrap_or("an anonymous volume")
+            );
+        }
 
-            if !confirmed {
-                outro("Operation cancelled".to_string()).into_diagnostic()?;
-                return Ok(());
-            }
+        let consequence = if keep_data {
+            "This will remove the container, but keep its data volume."
+        } else {
+            "This will remove the container and all its volumes."
+        };
+        let confirmed = self.confirm(
+            "destroy the container",
+            force,
+            format!(
+                "Are you sure you want to destroy container '{}'? {}",
+                project.container_name(),
+                consequence
+            ),
+        )?;
+        if !confirmed {
+            return self.report("destroy", project, Outcome::Cancelled, "");
         }
 
-        println!("{}", "Destroying container...".cyan());
+        // checked up front, so a data directory that is not safe to remove leaves the instance alone
+        let data_dir = match instance.storage() {
+            Some(DataStorage::HostDir(dir)) if !keep_data => {
+                utils::removable_data_dir(&project.path, &dir)?
+            }
+            _ => None,
+        };
+
+        self.ctx.progress("Destroying container...".cyan());
 
         // Stop if running
         if self
             .ctx
-            .docker
+            .runtime
             .is_container_running_by_id(&instance.container_id)
             .await?
         {
             self.ctx
-                .docker
+                .runtime
                 .stop_container(&instance.container_id, 5)
                 .await?;
         }
 
-        // Remove container
+        // Remove container, anonymous volumes of older instances go along with it
         self.ctx
-            .docker
-            .remove_container(&instance.container_id, true)
+            .runtime
+            .remove_container(&instance.container_id, true, !keep_data)
             .await?;
 
+        match instance.storage() {
+            Some(storage) if keep_data => {
+                self.ctx.progress(format!(
+                    "{} {}",
+                    "Kept data in".cyan(),
+                    storage.to_string().yellow()
+                ));
+            }
+            Some(DataStorage::Volume(volume)) => self.ctx.runtime.remove_volume(&volume).await?,
+            Some(DataStorage::HostDir(_)) | None => {}
+        }
+        if let Some(dir) = data_dir {
+            std::fs::remove_dir_all(&dir)
+                .into_diagnostic()
+                .wrap_err_with(|| format!("Failed to remove {}", dir.display()))?;
+        }
+
         // Remove from state
         self.ctx.state.remove(&project.name);
         self.ctx.state.save()?;
 
-        println!(
-            "{} {} {}",
-            "✓".green().bold(),
-            "Destroyed container".green(),
-            project.container_name().yellow()
-        );
-
-        Ok(())
+        self.report("destroy", project, Outcome::Done, "Destroyed container")
     }
 
     pub async fn wipe(&self, force: bool) -> Result<()> {
         let instance = self.ctx.require_instance()?;
         let project = self.ctx.require_project()?;
 
-        if !force {
-            use cliclack::{confirm, outro};
-            let confirmed = confirm(
-                "Are you sure you want to wipe all database data? This action cannot be undone."
-                    .to_string(),
-            )
-            .interact()
-            .into_diagnostic()?;
-
-            if !confirmed {
-                outro("Operation cancelled".to_string()).into_diagnostic()?;
-                return Ok(());
-            }
+        let confirmed = self.confirm(
+            "wipe the database",
+            force,
+            "Are you sure you want to wipe all database data? This action cannot be undone."
+                .to_string(),
+        )?;
+        if !confirmed {
+            return self.report("wipe", project, Outcome::Cancelled, "");
         }
 
         let is_running = self
             .ctx
-            .docker
+            .runtime
             .is_container_running_by_id(&instance.container_id)
             .await?;
 
         if !is_running {
-            println!("{}", "Starting container to wipe data...".cyan());
             self.ctx
-                .docker
-                .start_container_by_id(&instance.container_id)
+                .progress("Starting container to wipe data...".cyan());
+            self.ctx
+                .runtime
+                .start_container(&instance.container_id)
                 .await?;
             tokio::time::sleep(tokio::time::Duration::from_secs(3)).await;
         }
 
-        println!("{}", "Wiping database...".cyan());
+        self.ctx.progress("Wiping database...".cyan());
 
         // Drop and recreate database
         let drop_query = format!("DROP DATABASE IF EXISTS {};", DATABASE);
-        let drop_cmd = vec!["psql", "-U", USERNAME, "-c", &drop_query];
+        let drop_cmd = vec!["psql", "-U", USERNAME, "-d", "template1", "-c", &drop_query];
         self.ctx
-            .docker
-            .exec_in_container(&instance.container_id, drop_cmd)
+            .runtime
+            .exec_in_container(&instance.container_id, drop_cmd, vec![])
             .await?;
 
         let create_query = format!("CREATE DATABASE {};", DATABASE);
-        let create_cmd = vec!["psql", "-U", USERNAME, "-c", &create_query];
+        let create_cmd = vec![
+            "psql",
+            "-U",
+            USERNAME,
+            "-d",
+            "template1",
+            "-c",
+            &create_query,
+        ];
         self.ctx
-            .docker
-            .exec_in_container(&instance.container_id, create_cmd)
+            .runtime
+            .exec_in_container(&instance.container_id, create_cmd, vec![])
             .await?;
 
-        println!(
-            "{} {} {}",
-            "✓".green().bold(),
-            "Wiped database for".green(),
-            project.name.yellow()
-        );
+        self.report("wipe", project, Outcome::Done, "Wiped database for")
+    }
+
+    pub async fn rotate_password(&self) -> Result<()> {
+        let mut project = self.ctx.require_project()?.clone();
+        let reconciler = Reconciler { ctx: &self.ctx };
+
+        self.ctx.progress("Rotating password...".cyan());
+
+        // config goes first: if applying fails, the drift is picked up by the next start
+        project.config.password = utils::generate_password();
+        project.save_config()?;
+
+        reconciler.reconcile(&project).await?;
+
+        self.report(
+            "rotate-password",
+            &project,
+            Outcome::Done,
+            "Rotated password for",
+        )
+    }
+
+    /// Ask before a destructive action, unless `--force` or `--yes` already answered.
+    /// Without a terminal there is nobody to ask, so the action is refused
+    fn confirm(&self, action: &'static str, force: bool, prompt: String) -> Result<bool> {
+        if force || self.ctx.options.yes {
+            return Ok(true);
+        }
+
+        if !self.ctx.interactive() {
+            return Err(Error::ConfirmationRequired { action }.into());
+        }
+
+        let confirmed = cliclack::confirm(prompt).interact().into_diagnostic()?;
+        if !confirmed {
+            cliclack::outro("Operation cancelled".to_string()).into_diagnostic()?;
+        }
+
+        Ok(confirmed)
+    }
+
+    /// Closing line of a lifecycle command, or its JSON counterpart.
+    /// Unchanged and cancelled outcomes are already explained in text mode
+    fn report(
+        &self,
+        action: &'static str,
+        project: &Project,
+        outcome: Outcome,
+        message: &str,
+    ) -> Result<()> {
+        if self.ctx.json() {
+            return print_json(&ActionReport {
+                action,
+                project: project.name.clone(),
+                container_name: project.container_name(),
+                outcome,
+            });
+        }
+
+        if outcome == Outcome::Done {
+            println!(
+                "{} {} {}",
+                "✓".green().bold(),
+                message.green(),
+                project.container_name().yellow()
+            );
+        }
 
         Ok(())
     }
 
     pub async fn status(&self) -> Result<()> {
         let project = self.ctx.require_project()?;
+        let report = self.status_report(project).await?;
+
+        if self.ctx.json() {
+            return print_json(&report);
+        }
 
-        let mut table = create_ui_table(format!("Status: {}", project.name));
+        let mut table = create_ui_table(format!("Status: {}", report.project));
 
         table.add_row(vec![
             Cell::new("Project").fg(Color::White),
-            Cell::new(&project.name).add_attribute(Attribute::Bold),
+            Cell::new(&report.project).add_attribute(Attribute::Bold),
         ]);
 
         table.add_row(vec![
             Cell::new("Container Name").fg(Color::White),
-            Cell::new(project.container_name()).add_attribute(Attribute::Bold),
+            Cell::new(&report.container_name).add_attribute(Attribute::Bold),
         ]);
 
-        match &self.ctx.instance {
-            Some(instance) => {
-                let exists = self
-                    .ctx
-                    .docker
-                    .container_exists_by_id(&instance.container_id)
-                    .await?;
-
-                if !exists {
-                    table.add_row(vec![
-                        Cell::new("Status").fg(Color::White),
-                        Cell::new("Container not found").fg(Color::Red),
-                    ]);
-                } else {
-                    let is_running = self
-                        .ctx
-                        .docker
-                        .is_container_running_by_id(&instance.container_id)
-                        .await?;
-
-                    table.add_row(vec![
-                        Cell::new("Status").fg(Color::White),
-                        if is_running {
-                            Cell::new("Running").fg(Color::Green)
-                        } else {
-                            Cell::new("Stopped").fg(Color::Yellow)
-                        },
-                    ]);
-
-                    table.add_row(vec![
-                        Cell::new("Container ID").fg(Color::White),
-                        Cell::new(&instance.container_id[..12]).fg(Color::DarkGrey),
-                    ]);
-
-                    table.add_row(vec![
-                        Cell::new("PostgreSQL Version").fg(Color::White),
-                        Cell::new(instance.postgres_version.to_string())
-                            .add_attribute(Attribute::Bold),
-                    ]);
-
-                    table.add_row(vec![
-                        Cell::new("Port").fg(Color::White),
-                        Cell::new(instance.port.to_string()).add_attribute(Attribute::Bold),
-                    ]);
-
-                    // Check for drift
-                    if instance.postgres_version != project.config.version {
-                        table.add_row(vec![
-                            Cell::new("⚠ Version Drift").fg(Color::Yellow),
-                            Cell::new(format!(
-                                "Config: {}, Container: {}",
-                                project.config.version, instance.postgres_version
-                            ))
-                            .fg(Color::Yellow),
-                        ]);
-                    }
+        let status = match report.status {
+            InstanceStatus::Running => Cell::new("Running").fg(Color::Green),
+            InstanceStatus::Stopped => Cell::new("Stopped").fg(Color::Yellow),
+            InstanceStatus::ContainerMissing => Cell::new("Container not found").fg(Color::Red),
+            InstanceStatus::NotInitialized => Cell::new("Not initialized").fg(Color::Yellow),
+            InstanceStatus::OtherDaemon => Cell::new(format!(
+                "On another daemon: {}",
+                report.daemon_host.as_deref().unwrap_or_default()
+            ))
+            .fg(Color::Yellow),
+        };
+        table.add_row(vec![Cell::new("Status").fg(Color::White), status]);
 
-                    if instance.port != project.config.port {
-                        table.add_row(vec![
-                            Cell::new("⚠ Port Drift").fg(Color::Yellow),
-                            Cell::new(format!(
-                                "Config: {}, Container: {}",
-                                project.config.port, instance.port
-                            ))
-                            .fg(Color::Yellow),
-                        ]);
-                    }
-                }
+        if matches!(
+            report.status,
+            InstanceStatus::Running | InstanceStatus::Stopped
+        ) {
+            if let Some(container_id) = &report.container_id {
+                table.add_row(vec![
+                    Cell::new("Container ID").fg(Color::White),
+                    Cell::new(&container_id[..12]).fg(Color::DarkGrey),
+                ]);
+            }
+
+            if let Some(version) = &report.postgres_version {
+                table.add_row(vec![
+                    Cell::new("PostgreSQL Version").fg(Color::White),
+                    Cell::new(version).add_attribute(Attribute::Bold),
+                ]);
+            }
+
+            if let Some(port) = report.port {
+                table.add_row(vec![
+                    Cell::new("Port").fg(Color::White),
+                    Cell::new(port.to_string()).add_attribute(Attribute::Bold),
+                ]);
+            }
+
+            if let Some(data) = &report.data {
+                table.add_row(vec![
+                    Cell::new("Data").fg(Color::White),
+                    Cell::new(data).fg(Color::DarkGrey),
+                ]);
             }
-            None => {
+
+            for drift in &report.drift {
+                let details = match drift.field {
+                    DriftField::Password => {
+                        "Config password is not applied to the database".to_string()
+                    }
+                    _ => format!(
+                        "Config: {}, Container: {}",
+                        drift.config.as_deref().unwrap_or("none"),
+                        drift.container.as_deref().unwrap_or("none")
+                    ),
+                };
+
                 table.add_row(vec![
-                    Cell::new("Status").fg(Color::White),
-                    Cell::new("Not initialized").fg(Color::Yellow),
+                    Cell::new(format!("⚠ {} Drift", drift.field.label())).fg(Color::Yellow),
+                    Cell::new(details).fg(Color::Yellow),
                 ]);
             }
         }
@@ -394,27 +785,223 @@ impl Controller {
         Ok(())
     }
 
+    async fn status_report(&self, project: &Project) -> Result<StatusReport> {
+        let mut report = StatusReport {
+            project: project.name.clone(),
+            container_name: project.container_name(),
+            status: InstanceStatus::NotInitialized,
+            container_id: None,
+            postgres_version: None,
+            port: None,
+            data: None,
+            daemon_host: None,
+            drift: Vec::new(),
+        };
+
+        let Some(instance) = &self.ctx.instance else {
+            return Ok(report);
+        };
+
+        report.daemon_host = instance.daemon_host.clone();
+        if self.ctx.ensure_same_daemon(instance).is_err() {
+            report.status = InstanceStatus::OtherDaemon;
+            return Ok(report);
+        }
+
+        let runtime = &self.ctx.runtime;
+        if !runtime
+            .container_exists_by_id(&instance.container_id)
+            .await?
+        {
+            report.status = InstanceStatus::ContainerMissing;
+            return Ok(report);
+        }
+
+        report.status = if runtime
+            .is_container_running_by_id(&instance.container_id)
+            .await?
+        {
+            InstanceStatus::Running
+        } else {
+            InstanceStatus::Stopped
+        };
+        report.container_id = Some(instance.container_id.clone());
+        report.postgres_version = Some(instance.postgres_version.to_string());
+        report.port = instance.port;
+
+        if let Some(storage) = instance.storage() {
+            report.data = Some(storage.to_string());
+
+            if !storage.satisfies(&project.data_storage()) {
+                report.drift.push(Drift {
+                    field: DriftField::Storage,
+                    config: Some(project.data_storage().to_string()),
+                    container: Some(storage.to_string()),
+                });
+            }
+        }
+
+        // Check for drift
+        if instance.postgres_version != project.config.version {
+            report.drift.push(Drift {
+                field: DriftField::Version,
+                config: Some(project.config.version.to_string()),
+                container: Some(instance.postgres_version.to_string()),
+            });
+        }
+
+        if instance.port != project.config.port {
+            report.drift.push(Drift {
+                field: DriftField::Port,
+                config: project.config.port.map(|port| port.to_string()),
+                container: instance.port.map(|port| port.to_string()),
+            });
+        }
+
+        if instance.port.is_some() && instance.bind_address() != project.config.bind_address() {
+            report.drift.push(Drift {
+                field: DriftField::Bind,
+                config: Some(project.config.bind_address().to_string()),
+                container: Some(instance.bind_address().to_string()),
+            });
+        }
+
+        if instance.socket_dir != project.socket_path() {
+            let display = |dir: PathBuf| dir.display().to_string();
+            report.drift.push(Drift {
+                field: DriftField::SocketDir,
+                config: project.socket_path().map(display),
+                container: instance.socket_dir.clone().map(display),
+            });
+        }
+
+        let limits = project.config.limits();
+        let size = |size: Option<ByteSize>| size.map(|size| size.to_string());
+        for (field, config, container) in [
+            (
+                DriftField::Memory,
+                size(limits.memory),
+                size(instance.limits.memory),
+            ),
+            (
+                DriftField::Cpus,
+                limits.cpus.map(|cpus| cpus.to_string()),
+                instance.limits.cpus.map(|cpus| cpus.to_string()),
+            ),
+            (
+                DriftField::ShmSize,
+                size(limits.shm_size),
+                size(instance.limits.shm_size),
+            ),
+        ] {
+            if config != container {
+                report.drift.push(Drift {
+                    field,
+                    config,
+                    container,
+                });
+            }
+        }
+
+        if instance.tls_dir.is_some() != project.config.tls {
+            report.drift.push(Drift {
+                field: DriftField::Tls,
+                config: Some(project.config.tls.to_string()),
+                container: Some(instance.tls_dir.is_some().to_string()),
+            });
+        }
+
+        let fingerprint = utils::password_fingerprint(&project.config.password);
+        if instance.password_fingerprint.as_deref() != Some(fingerprint.as_str()) {
+            report.drift.push(Drift {
+                field: DriftField::Password,
+                config: None,
+                container: None,
+            });
+        }
+
+        Ok(report)
+    }
+
+    /// Run a command against a throwaway instance and return its exit code.
+    /// Never touches the state file, the container is removed afterwards
+    pub async fn ephemeral(&self, version: Option<String>, cmd: Vec<String>) -> Result<i32> {
+        let version = version.or_else(|| self.ctx.user.default_version.clone());
+
+        // the handler is installed on the first poll, before the container exists,
+        // so Ctrl-C while the image is pulled or the container created doesn't leak it
+        let interrupted = tokio::signal::ctrl_c();
+        tokio::pin!(interrupted);
+
+        let name = EphemeralInstance::container_name();
+        let created =
+            EphemeralInstance::create_as(self.ctx.runtime.clone(), version.as_deref(), &name);
+        let instance = tokio::select! {
+            instance = created => instance?,
+            result = &mut interrupted => {
+                result.into_diagnostic()?;
+                eprintln!("{}", "Interrupted".yellow());
+                EphemeralInstance::remove_unfinished(self.ctx.runtime.as_ref(), &name).await?;
+                return Ok(130);
+            }
+        };
+
+        eprintln!(
+            "{} {} {}",
+            "Starting ephemeral PostgreSQL".cyan(),
+            instance.version().to_string().yellow(),
+            format!("on port {}", instance.port()).cyan()
+        );
+
+        let outcome = tokio::select! {
+            outcome = run_against(&instance, &cmd) => outcome,
+            _ = &mut interrupted => {
+                eprintln!("{}", "Interrupted".yellow());
+                Ok(130)
+            }
+        };
+
+        eprintln!("{}", "Removing ephemeral instance...".cyan());
+        instance.cleanup().await?;
+
+        outcome
+    }
+
     pub async fn init_project(&self) -> Result<()> {
         let reconciler = Reconciler { ctx: &self.ctx };
 
         if let Some(project) = &self.ctx.project {
-            return reconciler.reconcile(project).await;
+            utils::ignore_host_dirs(project)?;
+            reconciler.reconcile(project).await?;
+            warn_if_exposed(project);
+            return self.report_init(project, false);
         }
 
-        println!("{}", "Initializing new pgd project...".cyan());
+        self.ctx.progress("Initializing new pgd project...".cyan());
 
-        let mut versions = self.ctx.docker.available_versions().await?;
-        versions.sort();
-        let latest_version = versions
-            .last()
-            .ok_or(miette!("expected to have at least one version"))?;
+        let versions = self.ctx.runtime.available_versions().await?;
+        let version = utils::resolve_version(self.ctx.user.default_version.as_deref(), &versions)?;
 
+        let allocator = PortAllocator::new(&self.ctx.state, &self.ctx.user)?;
         let config = PGDConfig {
-            version: *latest_version,
+            version,
             password: utils::generate_password(),
-            port: utils::find_available_port(&self.ctx.state)?,
+            port: Some(allocator.allocate(&Project::current_name()?, &[])?),
+            bind: None,
+            socket_dir: None,
+            tls: false,
+            memory: None,
+            cpus: None,
+            shm_size: None,
+            data_dir: None,
+            runtime: None,
         };
-        let project = Project::new(config)?;
+        let mut project = Project::new(config)?;
+
+        if self.ctx.json() {
+            self.reconcile_new(&mut project, &allocator).await?;
+            return self.report_init(&project, true);
+        }
 
         println!(
             "\nCreated pgd.toml in {}\n",
@@ -430,10 +1017,12 @@ impl Controller {
             Cell::new("PostgreSQL Version").fg(Color::White),
             Cell::new(project.config.version.to_string()).add_attribute(Attribute::Bold),
         ]);
-        table.add_row(vec![
-            Cell::new("Port").fg(Color::White),
-            Cell::new(project.config.port.to_string()).add_attribute(Attribute::Bold),
-        ]);
+        if let Some(port) = project.config.port {
+            table.add_row(vec![
+                Cell::new("Port").fg(Color::White),
+                Cell::new(port.to_string()).add_attribute(Attribute::Bold),
+            ]);
+        }
         table.add_row(vec![
             Cell::new("Password").fg(Color::White),
             Cell::new("*".repeat(project.config.password.len())).fg(Color::DarkGrey),
@@ -441,7 +1030,7 @@ impl Controller {
 
         println!("{table}");
 
-        reconciler.reconcile(&project).await?;
+        self.reconcile_new(&mut project, &allocator).await?;
 
         println!(
             "\n{} {}",
@@ -451,9 +1040,84 @@ impl Controller {
 
         Ok(())
     }
+
+    /// Writes the port back to pgd.toml if it had to move off a taken one
+    async fn reconcile_new(
+        &self,
+        project: &mut Project,
+        allocator: &PortAllocator<'_>,
+    ) -> Result<()> {
+        let picked = project.config.port;
+        let result = Reconciler { ctx: &self.ctx }
+            .reconcile_on_free_port(project, allocator)
+            .await;
+
+        if project.config.port != picked {
+            project.save_config()?;
+        }
+        result
+    }
+
+    fn report_init(&self, project: &Project, created: bool) -> Result<()> {
+        if !self.ctx.json() {
+            return Ok(());
+        }
+
+        print_json(&InitReport {
+            project: project.name.clone(),
+            path: project.path.clone(),
+            postgres_version: project.config.version.to_string(),
+            port: project.config.port,
+            created,
+        })
+    }
+}
+
+async fn run_against(instance: &EphemeralInstance, cmd: &[String]) -> Result<i32> {
+    instance.start().await?;
+
+    let (program, args) = cmd
+        .split_first()
+        .ok_or(miette!("expected a command to run"))?;
+
+    // killed if ctrl-c drops this future
+    let status = tokio::process::Command::new(program)
+        .args(args)
+        .envs(instance.env())
+        .kill_on_drop(true)
+        .status()
+        .await
+        .into_diagnostic()
+        .wrap_err_with(|| format!("Failed to run {program}"))?;
+
+    // no code means the command was killed by a signal
+    Ok(status.code().unwrap_or(128))
+}
+
+/// Published beyond loopback, anyone on the network who knows the password can connect
+fn warn_if_exposed(project: &Project) {
+    let Some(address) = project.config.address() else {
+        return;
+    };
+    if address.ip().is_loopback() {
+        return;
+    }
+
+    // on stderr, so it shows up in JSON mode too
+    eprintln!(
+        "{} {} {} {}",
+        "⚠".yellow().bold(),
+        project.container_name().yellow(),
+        format!(
+            "listens on {}, reachable from other machines on the network.",
+            address
+        )
+        .yellow(),
+        "Keep the password in pgd.toml secret, or set `bind = \"127.0.0.1\"`".yellow()
+    );
 }
 
-fn format_conn_human(project: &Project) {
+fn format_conn_human(project: &Project, host: IpAddr, root_cert: Option<&Path>) {
     let mut table = create_ui_table("Instance".to_string());
     table.add_row(vec![
         Cell::new("Project").fg(Color::White),
@@ -463,15 +1127,29 @@ fn format_conn_human(project: &Project) {
         Cell::new("PostgreSQL Version").fg(Color::White),
         Cell::new(project.config.version.to_string()).add_attribute(Attribute::Bold),
     ]);
-    table.add_row(vec![
-        Cell::new("Host").fg(Color::White),
-        Cell::new("127.0.0.1").add_attribute(Attribute::Bold),
-    ]);
+    if let Some(port) = project.config.port {
+        table.add_row(vec![
+            Cell::new("Host").fg(Color::White),
+            Cell::new(host.to_string()).add_attribute(Attribute::Bold),
+        ]);
 
-    table.add_row(vec![
-        Cell::new("Port").fg(Color::White),
-        Cell::new(project.config.port.to_string()).add_attribute(Attribute::Bold),
-    ]);
+        table.add_row(vec![
+            Cell::new("Port").fg(Color::White),
+            Cell::new(port.to_string()).add_attribute(Attribute::Bold),
+        ]);
+    }
+    if let Some(dir) = project.socket_path() {
+        table.add_row(vec![
+            Cell::new("Socket").fg(Color::White),
+            Cell::new(dir.display().to_string()).add_attribute(Attribute::Bold),
+        ]);
+    }
+    if let Some(root_cert) = root_cert {
+        table.add_row(vec![
+            Cell::new("Root Certificate").fg(Color::White),
+            Cell::new(root_cert.display().to_string()).add_attribute(Attribute::Bold),
+        ]);
+    }
     table.add_row(vec![
         Cell::new("Username").fg(Color::White),
         Cell::new(USERNAME).add_attribute(Attribute::Bold),
@@ -499,3 +1177,138 @@ fn create_ui_table(header: String) -> Table {
     table.set_style(BottomRightCorner, '╯');
     table
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::controller::runtime::{ContainerOwner, fake::FakeRuntime};
+
+    fn project(path: PathBuf, data_dir: Option<&str>) -> Project {
+        Project {
+            name: "app".to_string(),
+            path,
+            config: PGDConfig {
+                data_dir: data_dir.map(PathBuf::from),
+                ..PGDConfig::for_test("17.7", 5432)
+            },
+        }
+    }
+
+    /// Context of a project whose instance pgd already created
+    async fn existing_instance(runtime: &Arc<FakeRuntime>, project: &Project) -> Context {
+        let ctx = Context::with_runtime(
+            runtime.clone(),
+            Some(project.clone()),
+            None,
+            StateManager::in_memory(),
+        );
+        Reconciler { ctx: &ctx }.reconcile(project).await.unwrap();
+        Context::with_runtime(runtime.clone(), Some(project.clone()), None, ctx.state)
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_destroy_removes_volume() {
+        let runtime = Arc::new(FakeRuntime::new());
+        let project = project(PathBuf::from("/home/user/app"), None);
+        let controller = Controller::new(existing_instance(&runtime, &project).await);
+
+        controller.destroy(true, false).await.unwrap();
+
+        assert!(runtime.containers().is_empty());
+        assert!(runtime.volumes().is_empty());
+        assert!(controller.ctx.state.get("app").is_none());
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_destroy_keeps_volume() {
+        let runtime = Arc::new(FakeRuntime::new());
+        let project = project(PathBuf::from("/home/user/app"), None);
+        let controller = Controller::new(existing_instance(&runtime, &project).await);
+
+        controller.destroy(true, true).await.unwrap();
+
+        assert!(runtime.containers().is_empty());
+        assert_eq!(runtime.volumes(), vec![project.volume_name()]);
+        assert!(controller.ctx.state.get("app").is_none());
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_destroy_refuses_to_keep_anonymous_volume() {
+        let runtime = Arc::new(FakeRuntime::new());
+        let project = project(PathBuf::from("/home/user/app"), None);
+        let ctx = existing_instance(&runtime, &project).await;
+        // instance of a release that left PGDATA in an anonymous volume
+        let mut instance = ctx.state.get("app").unwrap();
+        runtime
+            .remove_container(&instance.container_id, true, false)
+            .await
+            .unwrap();
+        instance.container_id = runtime
+            .create_postgres_container(
+                &project.container_name(),
+                &project.config.version,
+                &project.config.password,
+                &project.listen(),
+                None,
+                &project.config.limits(),
+                &ContainerOwner::new(&project),
+            )
+            .await
+            .unwrap();
+        instance.volume_name = None;
+        ctx.state.upsert("app".to_string(), instance.clone());
+        let ctx = Context::with_runtime(runtime.clone(), Some(project), None, ctx.state);
+        let controller = Controller::new(ctx);
+
+        let err = controller.destroy(true, true).await.unwrap_err();
+
+        assert!(err.to_string().contains("volume anonymous-"));
+        assert!(runtime.container(&instance.container_id).is_some());
+        assert!(controller.ctx.state.get("app").is_some());
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_destroy_refuses_data_dir_outside_project() {
+        let root = std::env::temp_dir().join(format!("pgd-destroy-{}", std::process::id()));
+        let outside = root.join("other");
+        std::fs::create_dir_all(root.join("app")).unwrap();
+        // as if pgd.toml had been edited past the checks of `PGDConfig::load`
+        let project = project(root.join("app"), Some("../other"));
+        let runtime = Arc::new(FakeRuntime::new());
+        let ctx = existing_instance(&runtime, &project).await;
+        let instance = ctx.state.get("app").unwrap();
+
+        let result = Controller::new(ctx).destroy(true, false).await;
+
+        assert!(result.is_err());
+        assert!(outside.is_dir());
+        assert!(runtime.container(&instance.container_id).is_some());
+
+        std::fs::remove_dir_all(root).unwrap();
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_ephemeral_leaves_no_trace() {
+        let runtime = Arc::new(FakeRuntime::new());
+        let controller = Controller::new(Context::with_runtime(
+            runtime.clone(),
+            None,
+            None,
+            StateManager::in_memory(),
+        ));
+
+        let passed = controller
+            .ephemeral(None, vec!["true".to_string()])
+            .await
+            .unwrap();
+        let failed = controller
+            .ephemeral(Some("16".to_string()), vec!["false".to_string()])
+            .await
+            .unwrap();
+
+        assert_eq!((passed, failed), (0, 1));
+        assert!(runtime.containers().is_empty());
+        assert!(controller.ctx.state.instances().is_empty());
+        assert!(runtime.volumes().is_empty());
+    }
+}
@@ -0,0 +1,141 @@
+//! Explains why postgres keeps crashing, from its exit status and the tail of its log
+
+use crate::controller::runtime::ContainerInfo;
+
+/// Log lines attached to a crash loop diagnostic
+pub const LOG_TAIL_LINES: usize = 20;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CrashCause {
+    OutOfMemory,
+    IncompatibleDataVersion,
+    CorruptDataDir,
+    DataDirPermissions,
+    InvalidSetting,
+}
+
+/// Log messages of postgres and of the image entrypoint, matched case-insensitively
+const PATTERNS: &[(CrashCause, &str)] = &[
+    (
+        CrashCause::IncompatibleDataVersion,
+        "database files are incompatible with server",
+    ),
+    (
+        CrashCause::IncompatibleDataVersion,
+        "data directory was initialized by postgresql version",
+    ),
+    (
+        CrashCause::CorruptDataDir,
+        "could not locate a valid checkpoint record",
+    ),
+    (CrashCause::CorruptDataDir, "invalid checkpoint record"),
+    (CrashCause::CorruptDataDir, "is not a valid data directory"),
+    (CrashCause::CorruptDataDir, "exists but is not empty"),
+    (CrashCause::CorruptDataDir, "invalid resource manager id"),
+    (CrashCause::DataDirPermissions, "has invalid permissions"),
+    (CrashCause::DataDirPermissions, "permission denied"),
+    (
+        CrashCause::InvalidSetting,
+        "unrecognized configuration parameter",
+    ),
+    (CrashCause::InvalidSetting, "invalid value for parameter"),
+    (CrashCause::InvalidSetting, "contains errors"),
+    (CrashCause::OutOfMemory, "out of memory"),
+    (
+        CrashCause::OutOfMemory,
+        "could not map anonymous shared memory",
+    ),
+];
+
+impl CrashCause {
+    pub fn summary(self) -> &'static str {
+        match self {
+            CrashCause::OutOfMemory => "postgres ran out of memory",
+            CrashCause::IncompatibleDataVersion => {
+                "data was created by another major version of postgres"
+            }
+            CrashCause::CorruptDataDir => "data directory is corrupt or not empty",
+            CrashCause::DataDirPermissions => "postgres cannot access its data directory",
+            CrashCause::InvalidSetting => "postgres rejected a configuration setting",
+        }
+    }
+
+    pub fn help(self) -> &'static str {
+        match self {
+            CrashCause::OutOfMemory => {
+                "Give Docker more memory, or lower shared_buffers and work_mem"
+            }
+            CrashCause::IncompatibleDataVersion => {
+                "Set version in pgd.toml to the major version the data was created with, or destroy the instance to start over"
+            }
+            CrashCause::CorruptDataDir => {
+                "Restore the data from a backup, or run `pgd instance destroy` to start over"
+            }
+            CrashCause::DataDirPermissions => {
+                "Make the data directory owned by the user running pgd, or remove it to let pgd create it"
+            }
+            CrashCause::InvalidSetting => "Fix or remove the setting named in the log",
+        }
+    }
+}
+
+/// The log line explaining the crash, by its index, falling back to the exit status alone
+pub fn classify(info: &ContainerInfo, lines: &[String]) -> Option<(Option<usize>, CrashCause)> {
+    let matched = lines.iter().enumerate().find_map(|(i, line)| {
+        let line = line.to_lowercase();
+        PATTERNS
+            .iter()
+            .find(|(_, pattern)| line.contains(pattern))
+            .map(|(cause, _)| (Some(i), *cause))
+    });
+
+    matched.or_else(|| {
+        // SIGKILL without a message is most often the kernel OOM killer
+        info.oom_killed.then_some((None, CrashCause::OutOfMemory))
+    })
+}
+
+/// Human summary of how the container exited
+pub fn exit_status(info: &ContainerInfo) -> String {
+    match (info.oom_killed, info.exit_code) {
+        (true, _) => "killed for running out of memory".to_string(),
+        (false, Some(code)) => format!("exit code {code}"),
+        (false, None) => "no exit code".to_string(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn lines(log: &str) -> Vec<String> {
+        log.lines().map(str::to_string).collect()
+    }
+
+    #[test]
+    fn test_classify() {
+        let info = ContainerInfo::default();
+        let log = lines(
+            "PostgreSQL Database directory appears to contain a database; Skipping initialization\n\
+             FATAL:  database files are incompatible with server\n\
+             DETAIL:  The data directory was initialized by PostgreSQL version 16",
+        );
+        assert_eq!(
+            classify(&info, &log),
+            Some((Some(1), CrashCause::IncompatibleDataVersion))
+        );
+
+        let log = lines("LOG:  unrecognized configuration parameter \"shared_bufers\"");
+        assert_eq!(
+            classify(&info, &log),
+            Some((Some(0), CrashCause::InvalidSetting))
+        );
+
+        let oom = ContainerInfo {
+            oom_killed: true,
+            ..Default::default()
+        };
+        assert_eq!(classify(&oom, &[]), Some((None, CrashCause::OutOfMemory)));
+        assert_eq!(classify(&info, &lines("LOG:  starting")), None);
+    }
+}
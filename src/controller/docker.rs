@@ -1,60 +1,243 @@
-use miette::{Diagnostic, miette};
-use std::str::FromStr;
-use thiserror::Error;
+use std::{
+    collections::HashMap,
+    net::SocketAddr,
+    path::{Path, PathBuf},
+    str::FromStr,
+    sync::Arc,
+};
 
+use async_trait::async_trait;
 use bollard::{
     Docker,
-    container::LogOutput,
+    models::PortBinding,
     query_parameters::{
-        CreateContainerOptions, CreateImageOptions, InspectContainerOptions, ListImagesOptions,
-        LogsOptions, StartContainerOptions, StopContainerOptions,
+        CreateContainerOptions, CreateImageOptions, InspectContainerOptions, ListContainersOptions,
+        ListImagesOptions, LogsOptions, StartContainerOptions, StopContainerOptions,
+        TagImageOptions,
     },
     secret::ContainerCreateBody,
 };
 use colored::Colorize;
-use futures::{Stream, StreamExt};
+use futures::{StreamExt, stream::BoxStream};
 use indicatif::MultiProgress;
 use miette::{Context, IntoDiagnostic, Result};
-use tracing::{debug, info};
+use tracing::debug;
 
 use crate::{
-    config::PostgresVersion,
+    config::{DataStorage, LOOPBACK, Limits, Listen, PostgresVersion, RuntimeKind},
     consts::{DATABASE, USERNAME},
+    controller::{
+        runtime::{
+            ContainerInfo, ContainerOwner, ContainerRuntime, DaemonInfo, ExecOutput, PROJECT_LABEL,
+            SOCKET_MOUNT_PATH, TLS_MOUNT_PATH, VERSION_LABEL, data_mount_path, instance_labels,
+        },
+        tls::{SERVER_CERT, SERVER_KEY},
+    },
+    error,
 };
 
 mod download;
+pub mod endpoint;
+
+pub use endpoint::DockerEndpoint;
 
 const DOCKERHUB_POSTGRES: &str = "postgres";
+const DOCKERHUB_REGISTRY: &str = "docker.io/library";
+const PODMAN_SOCKET: &str = "podman/podman.sock";
+const PODMAN_ROOTFUL_SOCKET: &str = "/run/podman/podman.sock";
+const DOCKER_SOCKET: &str = "/var/run/docker.sock";
+
 fn format_image(ver: &PostgresVersion) -> String {
     format!("{DOCKERHUB_POSTGRES}:{}", ver)
 }
 
-#[derive(Error, Debug, Diagnostic)]
-#[error("Docker operation failed")]
-#[diagnostic(code(pgd::docker))]
-pub enum Error {}
+/// Podman lists images by their fully qualified name
+fn is_same_image(tag: &str, image: &str) -> bool {
+    tag == image
+        || tag
+            .strip_prefix(DOCKERHUB_REGISTRY)
+            .and_then(|t| t.strip_prefix('/'))
+            == Some(image)
+}
+
+/// Version of a `postgres:X.Y` tag, qualified or not
+fn parse_image(tag: &str) -> Option<PostgresVersion> {
+    let tag = tag
+        .strip_prefix(DOCKERHUB_REGISTRY)
+        .and_then(|t| t.strip_prefix('/'))
+        .unwrap_or(tag);
+
+    tag.strip_prefix(DOCKERHUB_POSTGRES)?
+        .strip_prefix(':')?
+        .parse()
+        .ok()
+}
+
+/// Registries answer unknown tags and missing credentials alike, with 401, 403 or 404
+fn is_pull_denied(err: &miette::Report) -> bool {
+    const MARKERS: [&str; 5] = [
+        "denied",
+        "unauthorized",
+        "not found",
+        "manifest unknown",
+        "does not exist",
+    ];
+
+    err.chain().any(|cause| {
+        let message = cause.to_string().to_lowercase();
+        MARKERS.iter().any(|marker| message.contains(marker))
+    })
+}
+
+/// Rootless socket, unless `CONTAINER_HOST` points elsewhere
+pub(crate) fn podman_socket() -> Option<PathBuf> {
+    if let Some(host) = std::env::var_os("CONTAINER_HOST")
+        && let Some(path) = host.to_string_lossy().strip_prefix("unix://")
+    {
+        return Some(PathBuf::from(path));
+    }
+
+    let rootless =
+        std::env::var_os("XDG_RUNTIME_DIR").map(|dir| PathBuf::from(dir).join(PODMAN_SOCKET));
+    let rootful = PathBuf::from(PODMAN_ROOTFUL_SOCKET);
+
+    rootless
+        .into_iter()
+        .chain(std::iter::once(rootful))
+        .find(|path| path.exists())
+}
+
+/// `PGD_RUNTIME` wins over pgd.toml. Without either, Docker is preferred when
+/// its daemon looks reachable, then Podman if its API socket exists
+pub fn resolve_runtime(configured: Option<RuntimeKind>) -> Result<RuntimeKind> {
+    if let Ok(env) = std::env::var("PGD_RUNTIME") {
+        return env.parse().wrap_err("Invalid PGD_RUNTIME");
+    }
+
+    if let Some(kind) = configured {
+        return Ok(kind);
+    }
+
+    let has_docker =
+        std::env::var_os("DOCKER_HOST").is_some() || std::path::Path::new(DOCKER_SOCKET).exists();
+    if !has_docker && podman_socket().is_some() {
+        debug!("Docker socket not found, falling back to podman");
+        return Ok(RuntimeKind::Podman);
+    }
+
+    Ok(RuntimeKind::Docker)
+}
+
+/// `uid:gid` owning a directory, used to run postgres as the invoking user
+#[cfg(unix)]
+fn host_dir_owner(dir: &std::path::Path) -> Result<String> {
+    use std::os::unix::fs::MetadataExt;
+
+    let meta = std::fs::metadata(dir)
+        .into_diagnostic()
+        .wrap_err_with(|| format!("Failed to read {}", dir.display()))?;
+    Ok(format!("{}:{}", meta.uid(), meta.gid()))
+}
+
+#[cfg(not(unix))]
+fn host_dir_owner(_dir: &std::path::Path) -> Result<String> {
+    miette::bail!("data_dir is only supported on unix hosts")
+}
+
+fn postgres_env(password: &str) -> Vec<String> {
+    vec![
+        format!("POSTGRES_PASSWORD={}", password),
+        format!("POSTGRES_USER={}", USERNAME),
+        format!("POSTGRES_DB={}", DATABASE),
+    ]
+}
+
+/// The mounted key keeps the owner and mode it has on the host, while postgres wants to
+/// own it and refuses keys others can read, so the server starts from a private copy
+fn tls_cmd() -> Vec<String> {
+    let key = "/tmp/pgd-server.key";
+    let script = format!(
+        "cp {TLS_MOUNT_PATH}/{SERVER_KEY} {key} && chmod 600 {key} && \
+         {{ [ \"$(id -u)\" != 0 ] || chown postgres:postgres {key}; }} && \
+         exec docker-entrypoint.sh postgres -c ssl=on \
+         -c ssl_cert_file={TLS_MOUNT_PATH}/{SERVER_CERT} -c ssl_key_file={key}"
+    );
+    vec!["sh".to_string(), "-c".to_string(), script]
+}
+
+fn postgres_labels(version: &PostgresVersion) -> HashMap<String, String> {
+    let mut labels = HashMap::new();
+    labels.insert(VERSION_LABEL.to_string(), version.to_string());
+    labels
+}
+
+fn port_bindings(address: SocketAddr) -> HashMap<String, Option<Vec<PortBinding>>> {
+    let mut port_bindings = HashMap::new();
+    port_bindings.insert(
+        "5432/tcp".to_string(),
+        Some(vec![PortBinding {
+            host_ip: Some(address.ip().to_string()),
+            host_port: Some(address.port().to_string()),
+        }]),
+    );
+    port_bindings
+}
 
+/// Talks to the Docker API, either of the Docker daemon or of Podman's compatibility layer
+#[derive(Clone)]
 pub struct DockerController {
     daemon: Docker,
+    endpoint: DockerEndpoint,
+    kind: RuntimeKind,
+    registry_mirror: Option<String>,
 }
 
 impl DockerController {
-    pub async fn new() -> Result<Self> {
-        let docker = Docker::connect_with_local_defaults()
-    .into_diagnostic()
-    .wrap_err(
-        "Failed to connect to Docker! pgd required Docker installed. Make sure it's running.",
-    )?;
+    /// Connect to the daemon `host_override` points to, or to the one the environment selects
+    pub async fn new(kind: RuntimeKind, host_override: Option<&str>) -> Result<Self> {
+        let endpoint = DockerEndpoint::resolve(kind, host_override)
+            .map_err(|cause| error::Error::DaemonUnavailable { kind, cause })?;
+        Self::connect(kind, endpoint).await
+    }
 
-        debug!("Connected to docker!");
+    pub async fn connect(kind: RuntimeKind, endpoint: DockerEndpoint) -> Result<Self> {
+        let docker = endpoint
+            .connect()
+            .map_err(|cause| error::Error::DaemonUnavailable { kind, cause })?;
+
+        debug!("Connected to {kind} at {}!", endpoint.host);
 
         docker
             .list_images(Some(ListImagesOptions::default()))
             .await
             .into_diagnostic()
-            .wrap_err("Docker basic connectivity test refused")?;
+            .wrap_err_with(|| format!("{kind} basic connectivity test refused"))
+            .map_err(|cause| error::Error::DaemonUnavailable { kind, cause })?;
+
+        Ok(Self {
+            daemon: docker,
+            endpoint,
+            kind,
+            registry_mirror: None,
+        })
+    }
 
-        Ok(Self { daemon: docker })
+    pub fn kind(&self) -> RuntimeKind {
+        self.kind
+    }
+
+    /// Pull images through `mirror` instead of Docker Hub
+    pub fn with_registry_mirror(mut self, mirror: Option<String>) -> Self {
+        self.registry_mirror = mirror.map(|m| m.trim_end_matches('/').to_string());
+        self
+    }
+
+    /// Podman may refuse short names, depending on its registries.conf
+    fn image(&self, ver: &PostgresVersion) -> String {
+        match self.kind {
+            RuntimeKind::Docker => format_image(ver),
+            RuntimeKind::Podman => format!("{DOCKERHUB_REGISTRY}/{}", format_image(ver)),
+        }
     }
 
     pub async fn download_image(&self, image: String) -> Result<()> {
@@ -67,18 +250,81 @@ impl DockerController {
 
         let multi = MultiProgress::new();
 
-        println!("{} {}", "Downloading".cyan(), image.yellow());
+        // stderr along with the progress bars, keeping stdout for command output
+        eprintln!("{} {}", "Downloading".cyan(), image.yellow());
 
-        download::perform_download(multi, download_progress).await?;
+        download::perform_download(multi, download_progress)
+            .await
+            .map_err(|cause| match is_pull_denied(&cause) {
+                true => error::Error::ImagePullDenied {
+                    image: image.clone(),
+                    cause,
+                }
+                .into(),
+                false => cause,
+            })?;
 
-        println!("{}", "Download complete!".green().bold());
+        eprintln!("{}", "Download complete!".green().bold());
 
         Ok(())
     }
 
-    pub async fn ensure_version_downloaded(&self, ver: &PostgresVersion) -> Result<()> {
-        let desired_image_tag = format_image(ver);
+    async fn create_container(
+        &self,
+        container_name: &str,
+        config: ContainerCreateBody,
+    ) -> Result<String> {
+        let options = CreateContainerOptions {
+            name: Some(container_name.to_owned()),
+            platform: String::new(),
+        };
+
+        let response = self
+            .daemon
+            .create_container(Some(options), config)
+            .await
+            .into_diagnostic()
+            .wrap_err("Failed to create container")?;
 
+        Ok(response.id)
+    }
+}
+
+#[async_trait]
+impl ContainerRuntime for DockerController {
+    async fn reconnect(&self) -> Result<Arc<dyn ContainerRuntime>> {
+        Ok(Arc::new(
+            Self::connect(self.kind, self.endpoint.clone()).await?,
+        ))
+    }
+
+    fn daemon_host(&self) -> String {
+        self.endpoint.host.clone()
+    }
+
+    async fn daemon_info(&self) -> Result<DaemonInfo> {
+        let version = self
+            .daemon
+            .version()
+            .await
+            .into_diagnostic()
+            .wrap_err("Failed to get daemon version")?;
+        let info = self
+            .daemon
+            .info()
+            .await
+            .into_diagnostic()
+            .wrap_err("Failed to get daemon info")?;
+
+        Ok(DaemonInfo {
+            version: version.version.unwrap_or_default(),
+            api_version: version.api_version.unwrap_or_default(),
+            system_time: info.system_time,
+            root_dir: info.docker_root_dir.map(PathBuf::from),
+        })
+    }
+
+    async fn postgres_images(&self) -> Result<Vec<(PostgresVersion, u64)>> {
         let images = self
             .daemon
             .list_images(Some(ListImagesOptions::default()))
@@ -86,152 +332,264 @@ impl DockerController {
             .into_diagnostic()
             .wrap_err("failed to list installed docker images")?;
 
-        let is_downloaded = images
+        Ok(images
             .iter()
-            .any(|img| img.repo_tags.contains(&desired_image_tag));
+            .filter_map(|img| {
+                let version = img.repo_tags.iter().find_map(|tag| parse_image(tag))?;
+                Some((version, img.size.max(0) as u64))
+            })
+            .collect())
+    }
+
+    async fn has_image(&self, ver: &PostgresVersion) -> Result<bool> {
+        let desired_image_tag = format_image(ver);
 
-        if !is_downloaded {
-            self.download_image(desired_image_tag).await?;
+        let images = self
+            .daemon
+            .list_images(Some(ListImagesOptions::default()))
+            .await
+            .into_diagnostic()
+            .wrap_err("failed to list installed docker images")?;
+
+        Ok(images.iter().any(|img| {
+            img.repo_tags
+                .iter()
+                .any(|tag| is_same_image(tag, &desired_image_tag))
+        }))
+    }
+
+    async fn ensure_version_downloaded(&self, ver: &PostgresVersion) -> Result<()> {
+        if self.has_image(ver).await? {
+            return Ok(());
         }
 
+        let Some(mirror) = &self.registry_mirror else {
+            return self.download_image(self.image(ver)).await;
+        };
+
+        // tagged under its Docker Hub name, so containers and `has_image` don't depend on the mirror
+        let mirrored = format!("{mirror}/library/{}", format_image(ver));
+        self.download_image(mirrored.clone()).await?;
+
+        let image = self.image(ver);
+        let (repo, tag) = image.rsplit_once(':').unwrap_or((&image, "latest"));
+        self.daemon
+            .tag_image(
+                &mirrored,
+                Some(TagImageOptions {
+                    repo: Some(repo.to_string()),
+                    tag: Some(tag.to_string()),
+                }),
+            )
+            .await
+            .into_diagnostic()
+            .wrap_err_with(|| format!("Failed to tag {mirrored} as {image}"))?;
+
         Ok(())
     }
 
     // TODO: make client to get available versions from dockerhub
-    pub async fn available_versions(&self) -> Result<Vec<PostgresVersion>> {
+    async fn available_versions(&self) -> Result<Vec<PostgresVersion>> {
         Ok(vec!["18.1", "17.7", "16.11", "15.15", "14.20"]
             .into_iter()
             .map(|v| PostgresVersion::from_str(v).unwrap())
             .collect())
     }
 
-    pub async fn container_exists(&self, container_id: &str) -> Result<bool> {
-        match self
-            .daemon
-            .inspect_container(container_id, None::<InspectContainerOptions>)
-            .await
-        {
-            Ok(_) => Ok(true),
-            Err(bollard::errors::Error::DockerResponseServerError {
-                status_code: 404, ..
-            }) => Ok(false),
-            Err(e) => Err(e)
-                .into_diagnostic()
-                .wrap_err("Failed to inspect container"),
+    async fn create_postgres_container(
+        &self,
+        container_name: &str,
+        version: &PostgresVersion,
+        password: &str,
+        listen: &Listen,
+        storage: Option<&DataStorage>,
+        limits: &Limits,
+        owner: &ContainerOwner,
+    ) -> Result<String> {
+        use bollard::models::{HostConfig, Mount, MountTypeEnum};
+
+        let mut host_config = HostConfig {
+            port_bindings: listen.tcp.map(port_bindings),
+            memory: limits.memory.map(|size| size.0 as i64),
+            nano_cpus: limits.cpus.map(|cpus| (cpus * 1e9) as i64),
+            shm_size: limits.shm_size.map(|size| size.0 as i64),
+            ..Default::default()
+        };
+
+        // without explicit storage the image creates an anonymous volume
+        let target = data_mount_path(version);
+        let mut mounts = Vec::new();
+        if let Some(DataStorage::Volume(volume)) = storage {
+            mounts.push(Mount {
+                target: Some(target.to_string()),
+                source: Some(volume.clone()),
+                typ: Some(MountTypeEnum::VOLUME),
+                ..Default::default()
+            });
         }
-    }
 
-    pub async fn is_container_running(&self, container_name: &str) -> Result<bool> {
-        let container = self
-            .daemon
-            .inspect_container(container_name, None::<InspectContainerOptions>)
-            .await
-            .into_diagnostic()
-            .wrap_err("Failed to inspect container")?;
+        let mut binds = Vec::new();
+        let mut bind_mount = |dir: &Path, target: &str, read_only: bool| {
+            // rootless podman needs the directory relabeled for SELinux, only possible through binds
+            if self.kind == RuntimeKind::Podman {
+                let options = if read_only { "ro,Z" } else { "Z" };
+                binds.push(format!("{}:{}:{}", dir.display(), target, options));
+            } else {
+                mounts.push(Mount {
+                    target: Some(target.to_string()),
+                    source: Some(dir.display().to_string()),
+                    typ: Some(MountTypeEnum::BIND),
+                    read_only: Some(read_only),
+                    ..Default::default()
+                });
+            }
+        };
+
+        if let Some(DataStorage::HostDir(dir)) = storage {
+            bind_mount(dir, target, false);
+        }
+        if let Some(dir) = &listen.socket_dir {
+            bind_mount(dir, SOCKET_MOUNT_PATH, false);
+        }
+        if let Some(dir) = &listen.tls {
+            bind_mount(dir, TLS_MOUNT_PATH, true);
+        }
+
+        host_config.mounts = (!mounts.is_empty()).then_some(mounts);
+        host_config.binds = (!binds.is_empty()).then_some(binds);
+
+        // files in a bind-mounted directory must stay accessible to its owner on the host
+        let user = match storage {
+            Some(DataStorage::HostDir(dir)) => {
+                // otherwise rootless podman maps the uid to one of the user's subuids
+                if self.kind == RuntimeKind::Podman {
+                    host_config.userns_mode = Some("keep-id".to_string());
+                }
+                Some(host_dir_owner(dir)?)
+            }
+            _ => None,
+        };
+
+        let config = ContainerCreateBody {
+            image: Some(self.image(version)),
+            user,
+            env: Some(postgres_env(password)),
+            cmd: listen.tls.is_some().then(tls_cmd),
+            host_config: Some(host_config),
+            labels: Some(instance_labels(version, listen, storage, owner)),
+            ..Default::default()
+        };
 
-        Ok(container.state.and_then(|s| s.running).unwrap_or(false))
+        self.create_container(container_name, config).await
     }
 
-    pub async fn create_postgres_container(
+    async fn create_ephemeral_container(
         &self,
         container_name: &str,
         version: &PostgresVersion,
         password: &str,
         port: u16,
     ) -> Result<String> {
-        use bollard::models::{HostConfig, PortBinding};
-        use std::collections::HashMap;
+        use bollard::models::HostConfig;
 
-        let image = format_image(version);
-
-        let env = vec![
-            format!("POSTGRES_PASSWORD={}", password),
-            format!("POSTGRES_USER={}", USERNAME),
-            format!("POSTGRES_DB={}", DATABASE),
-        ];
-
-        let mut port_bindings = HashMap::new();
-        port_bindings.insert(
-            "5432/tcp".to_string(),
-            Some(vec![PortBinding {
-                host_ip: Some("127.0.0.1".to_string()),
-                host_port: Some(port.to_string()),
-            }]),
-        );
+        let mut tmpfs = HashMap::new();
+        tmpfs.insert(data_mount_path(version).to_string(), "rw".to_string());
 
         let host_config = HostConfig {
-            port_bindings: Some(port_bindings),
+            port_bindings: Some(port_bindings(SocketAddr::new(LOOPBACK, port))),
+            tmpfs: Some(tmpfs),
             ..Default::default()
         };
 
-        let mut labels = HashMap::new();
-        labels.insert("pgd.postgres.version".to_string(), version.to_string());
+        let mut labels = postgres_labels(version);
+        labels.insert("pgd.ephemeral".to_string(), "true".to_string());
+
+        let cmd = [
+            "postgres",
+            "-c",
+            "fsync=off",
+            "-c",
+            "synchronous_commit=off",
+            "-c",
+            "full_page_writes=off",
+        ];
 
         let config = ContainerCreateBody {
-            image: Some(image),
-            env: Some(env),
+            image: Some(self.image(version)),
+            env: Some(postgres_env(password)),
+            cmd: Some(cmd.iter().map(|s| s.to_string()).collect()),
             host_config: Some(host_config),
             labels: Some(labels),
             ..Default::default()
         };
 
-        let options = CreateContainerOptions {
-            name: Some(container_name.to_owned()),
-            platform: String::new(),
-        };
-
-        let response = self
-            .daemon
-            .create_container(Some(options), config)
-            .await
-            .into_diagnostic()
-            .wrap_err("Failed to create container")?;
+        self.create_container(container_name, config).await
+    }
 
-        Ok(response.id)
+    async fn volume_exists(&self, volume_name: &str) -> Result<bool> {
+        match self.daemon.inspect_volume(volume_name).await {
+            Ok(_) => Ok(true),
+            Err(bollard::errors::Error::DockerResponseServerError {
+                status_code: 404, ..
+            }) => Ok(false),
+            Err(e) => Err(e)
+                .into_diagnostic()
+                .wrap_err_with(|| format!("Failed to inspect volume {volume_name}")),
+        }
     }
 
-    pub async fn start_container(&self, container_id: &str) -> Result<()> {
+    async fn ensure_volume(&self, volume_name: &str) -> Result<bool> {
+        use bollard::models::VolumeCreateOptions;
+
+        if self.volume_exists(volume_name).await? {
+            return Ok(false);
+        }
+
+        let mut labels = HashMap::new();
+        labels.insert("pgd.managed".to_string(), "true".to_string());
+
         self.daemon
-            .start_container(container_id, None::<StartContainerOptions>)
+            .create_volume(VolumeCreateOptions {
+                name: Some(volume_name.to_string()),
+                labels: Some(labels),
+                ..Default::default()
+            })
             .await
             .into_diagnostic()
-            .wrap_err("Failed to start container")?;
+            .wrap_err_with(|| format!("Failed to create volume {volume_name}"))?;
 
-        Ok(())
+        Ok(true)
     }
 
-    pub async fn container_exists_by_id(&self, container_id: &str) -> Result<bool> {
+    async fn remove_volume(&self, volume_name: &str) -> Result<()> {
+        use bollard::query_parameters::RemoveVolumeOptions;
+
         match self
             .daemon
-            .inspect_container(container_id, None::<InspectContainerOptions>)
+            .remove_volume(volume_name, None::<RemoveVolumeOptions>)
             .await
         {
-            Ok(_) => Ok(true),
-            Err(bollard::errors::Error::DockerResponseServerError {
+            Ok(_)
+            | Err(bollard::errors::Error::DockerResponseServerError {
                 status_code: 404, ..
-            }) => Ok(false),
+            }) => Ok(()),
             Err(e) => Err(e)
                 .into_diagnostic()
-                .wrap_err("Failed to inspect container by ID"),
+                .wrap_err_with(|| format!("Failed to remove volume {volume_name}")),
         }
     }
 
-    pub async fn is_container_running_by_id(&self, container_id: &str) -> Result<bool> {
-        let container = self
-            .daemon
-            .inspect_container(container_id, None::<InspectContainerOptions>)
+    async fn start_container(&self, container_id: &str) -> Result<()> {
+        self.daemon
+            .start_container(container_id, None::<StartContainerOptions>)
             .await
             .into_diagnostic()
-            .wrap_err("Failed to inspect container")?;
-
-        Ok(container.state.and_then(|s| s.running).unwrap_or(false))
-    }
+            .wrap_err("Failed to start container")?;
 
-    pub async fn start_container_by_id(&self, container_id: &str) -> Result<()> {
-        self.start_container(container_id).await
+        Ok(())
     }
 
-    pub async fn stop_container(&self, container_id: &str, timeout: i32) -> Result<()> {
+    async fn stop_container(&self, container_id: &str, timeout: i32) -> Result<()> {
         self.daemon
             .stop_container(
                 container_id,
@@ -247,48 +605,104 @@ impl DockerController {
         Ok(())
     }
 
-    pub async fn get_container_postgres_version(
-        &self,
-        container_id: &str,
-    ) -> Result<PostgresVersion> {
-        let container = self
-            .daemon
-            .inspect_container(container_id, None::<InspectContainerOptions>)
-            .await
-            .into_diagnostic()
-            .wrap_err("Failed to inspect container")?;
-
-        let labels = container
-            .config
-            .and_then(|c| c.labels)
-            .ok_or_else(|| miette!("Container has no labels"))?;
-
-        let version_str = labels
-            .get("pgd.postgres.version")
-            .ok_or_else(|| miette!("Container missing pgd.postgres.version label"))?;
-
-        PostgresVersion::from_str(version_str)
-            .map_err(|_| miette!("Invalid version in label: {}", version_str))
-    }
-
-    pub async fn stream_logs(
+    fn stream_logs(
         &self,
         container_id: &str,
         follow: bool,
-    ) -> impl Stream<Item = Result<LogOutput>> {
+        tail: Option<usize>,
+    ) -> BoxStream<'static, Result<String>> {
         let options = Some(LogsOptions {
             follow,
             stdout: true,
             stderr: true,
+            tail: tail.map_or_else(|| "all".to_string(), |lines| lines.to_string()),
             ..Default::default()
         });
 
         self.daemon
             .logs(container_id, options)
-            .map(|k| k.into_diagnostic().wrap_err("Failed streaming logs"))
+            .map(|k| {
+                k.into_diagnostic()
+                    .wrap_err("Failed streaming logs")
+                    .map(|log| String::from_utf8_lossy(log.into_bytes().as_ref()).into_owned())
+            })
+            .boxed()
+    }
+
+    async fn list_project_containers(&self) -> Result<Vec<(String, ContainerInfo)>> {
+        let options = ListContainersOptions {
+            all: true,
+            filters: Some(HashMap::from([(
+                "label".to_string(),
+                vec![PROJECT_LABEL.to_string()],
+            )])),
+            ..Default::default()
+        };
+        let summaries = self
+            .daemon
+            .list_containers(Some(options))
+            .await
+            .into_diagnostic()
+            .wrap_err("Failed to list containers")?;
+
+        let mut containers = Vec::new();
+        for id in summaries.into_iter().filter_map(|c| c.id) {
+            // removed in between
+            if let Some(info) = self.inspect_container(&id).await? {
+                containers.push((id, info));
+            }
+        }
+        Ok(containers)
+    }
+
+    async fn inspect_container(&self, container_id: &str) -> Result<Option<ContainerInfo>> {
+        use bollard::models::MountPointTypeEnum;
+
+        let container = match self
+            .daemon
+            .inspect_container(container_id, None::<InspectContainerOptions>)
+            .await
+        {
+            Ok(container) => container,
+            Err(bollard::errors::Error::DockerResponseServerError {
+                status_code: 404, ..
+            }) => return Ok(None),
+            Err(e) => {
+                return Err(e)
+                    .into_diagnostic()
+                    .wrap_err("Failed to inspect container");
+            }
+        };
+
+        let volumes = container
+            .mounts
+            .unwrap_or_default()
+            .into_iter()
+            .filter(|m| m.typ == Some(MountPointTypeEnum::VOLUME))
+            .filter_map(|m| Some((m.destination?, m.name?)))
+            .collect();
+
+        let state = container.state.unwrap_or_default();
+        let running = state.running.unwrap_or(false);
+
+        Ok(Some(ContainerInfo {
+            running,
+            labels: container.config.and_then(|c| c.labels).unwrap_or_default(),
+            volumes,
+            // a never started container reports 0 too
+            exit_code: state
+                .exit_code
+                .filter(|_| !running && state.finished_at.is_some()),
+            oom_killed: state.oom_killed.unwrap_or(false),
+        }))
     }
 
-    pub async fn remove_container(&self, container_id: &str, force: bool) -> Result<()> {
+    async fn remove_container(
+        &self,
+        container_id: &str,
+        force: bool,
+        remove_volumes: bool,
+    ) -> Result<()> {
         use bollard::query_parameters::RemoveContainerOptions;
 
         self.daemon
@@ -296,7 +710,7 @@ impl DockerController {
                 container_id,
                 Some(RemoveContainerOptions {
                     force,
-                    v: true, // Remove associated volumes
+                    v: remove_volumes,
                     ..Default::default()
                 }),
             )
@@ -307,25 +721,12 @@ impl DockerController {
         Ok(())
     }
 
-    pub async fn restart_container(&self, container_id: &str, timeout: i32) -> Result<()> {
-        use bollard::query_parameters::RestartContainerOptions;
-
-        self.daemon
-            .restart_container(
-                container_id,
-                Some(RestartContainerOptions {
-                    t: Some(timeout),
-                    signal: None,
-                }),
-            )
-            .await
-            .into_diagnostic()
-            .wrap_err("Failed to restart container")?;
-
-        Ok(())
-    }
-
-    pub async fn exec_in_container(&self, container_id: &str, cmd: Vec<&str>) -> Result<String> {
+    async fn exec_in_container(
+        &self,
+        container_id: &str,
+        cmd: Vec<&str>,
+        env: Vec<&str>,
+    ) -> Result<ExecOutput> {
         use bollard::container::LogOutput;
         use bollard::exec::{CreateExecOptions, StartExecOptions};
 
@@ -335,6 +736,7 @@ impl DockerController {
                 container_id,
                 CreateExecOptions {
                     cmd: Some(cmd),
+                    env: Some(env),
                     attach_stdout: Some(true),
                     attach_stderr: Some(true),
                     ..Default::default()
@@ -366,6 +768,14 @@ impl DockerController {
             }
         }
 
-        Ok(output)
+        let exit_code = self
+            .daemon
+            .inspect_exec(&exec.id)
+            .await
+            .into_diagnostic()
+            .wrap_err("Failed to inspect exec")?
+            .exit_code;
+
+        Ok(ExecOutput { exit_code, output })
     }
 }
@@ -0,0 +1,262 @@
+//! Which daemon to talk to, resolved like the docker CLI does
+
+use std::path::{Path, PathBuf};
+
+use bollard::Docker;
+use miette::{Context, IntoDiagnostic, Result, miette};
+use serde::Deserialize;
+use tracing::debug;
+
+use crate::config::RuntimeKind;
+
+use super::podman_socket;
+
+const CONNECT_TIMEOUT_SECS: u64 = 120;
+const DEFAULT_CONTEXT: &str = "default";
+
+#[cfg(unix)]
+const DEFAULT_DOCKER_HOST: &str = "unix:///var/run/docker.sock";
+#[cfg(windows)]
+const DEFAULT_DOCKER_HOST: &str = "npipe:////./pipe/docker_engine";
+
+/// Address of a Docker API, with client certificates when it is served over TLS
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DockerEndpoint {
+    /// `unix://`, `npipe://` or `tcp://` address
+    pub host: String,
+    /// Directory holding `ca.pem`, `cert.pem` and `key.pem`
+    pub tls: Option<PathBuf>,
+}
+
+#[derive(Deserialize)]
+struct DockerConfigFile {
+    #[serde(rename = "currentContext")]
+    current_context: Option<String>,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "PascalCase")]
+struct ContextMeta {
+    endpoints: ContextEndpoints,
+}
+
+#[derive(Deserialize)]
+struct ContextEndpoints {
+    docker: ContextEndpoint,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "PascalCase")]
+struct ContextEndpoint {
+    host: String,
+    #[serde(default)]
+    skip_tls_verify: bool,
+}
+
+impl DockerEndpoint {
+    /// `--docker-host` first, then `DOCKER_HOST`, `DOCKER_CONTEXT` and the current context
+    /// of `~/.docker/config.json`, falling back to the local socket.
+    /// Podman is reached through its own socket unless a host is given explicitly
+    pub fn resolve(kind: RuntimeKind, host_override: Option<&str>) -> Result<Self> {
+        if let Some(host) = host_override {
+            return Self::from_host(host);
+        }
+
+        if kind == RuntimeKind::Podman {
+            let socket = podman_socket().ok_or_else(|| {
+                miette!(
+                    help =
+                        "Enable the API socket with `systemctl --user enable --now podman.socket`",
+                    "Podman API socket not found"
+                )
+            })?;
+            return Ok(Self {
+                host: format!("unix://{}", socket.display()),
+                tls: None,
+            });
+        }
+
+        if let Some(host) = env_var("DOCKER_HOST") {
+            return Self::from_host(&host);
+        }
+
+        let config_dir = docker_config_dir()?;
+        let context = match env_var("DOCKER_CONTEXT") {
+            Some(context) => Some(context),
+            None => current_context(&config_dir)?,
+        };
+
+        match context {
+            Some(name) if name != DEFAULT_CONTEXT => Self::from_context(&config_dir, &name),
+            _ => Ok(Self {
+                host: DEFAULT_DOCKER_HOST.to_string(),
+                tls: None,
+            }),
+        }
+    }
+
+    /// TLS settings come from `DOCKER_TLS_VERIFY` and `DOCKER_CERT_PATH`
+    fn from_host(host: &str) -> Result<Self> {
+        let tls = match env_var("DOCKER_TLS_VERIFY") {
+            Some(_) => Some(match env_var("DOCKER_CERT_PATH") {
+                Some(path) => PathBuf::from(path),
+                None => docker_config_dir()?,
+            }),
+            None => None,
+        };
+
+        Ok(Self {
+            host: host.to_string(),
+            tls,
+        })
+    }
+
+    /// Context metadata lives in a directory named after the sha256 of the context name
+    fn from_context(config_dir: &Path, name: &str) -> Result<Self> {
+        let digest = context_digest(name);
+        let meta_path = config_dir
+            .join("contexts")
+            .join("meta")
+            .join(&digest)
+            .join("meta.json");
+
+        let content = std::fs::read_to_string(&meta_path).map_err(|_| {
+            miette!(
+                help = "List available contexts with `docker context ls`",
+                "Docker context `{}` not found",
+                name
+            )
+        })?;
+        let meta: ContextMeta = serde_json::from_str(&content)
+            .into_diagnostic()
+            .wrap_err_with(|| format!("Failed to parse {}", meta_path.display()))?;
+
+        let endpoint = meta.endpoints.docker;
+        if endpoint.skip_tls_verify {
+            debug!("SkipTLSVerify of context {name} is not supported, verifying anyway");
+        }
+
+        let tls_dir = config_dir
+            .join("contexts")
+            .join("tls")
+            .join(&digest)
+            .join("docker");
+
+        Ok(Self {
+            host: endpoint.host,
+            tls: tls_dir.is_dir().then_some(tls_dir),
+        })
+    }
+
+    pub fn connect(&self) -> Result<Docker> {
+        let version = bollard::API_DEFAULT_VERSION;
+        let host = self.host.as_str();
+
+        let docker = if host.starts_with("unix://") {
+            Docker::connect_with_unix(host, CONNECT_TIMEOUT_SECS, version)
+        } else if host.starts_with("npipe://") {
+            #[cfg(windows)]
+            {
+                Docker::connect_with_named_pipe(host, CONNECT_TIMEOUT_SECS, version)
+            }
+            #[cfg(not(windows))]
+            miette::bail!("Named pipes are only available on Windows: {}", host)
+        } else if ["tcp://", "http://", "https://"]
+            .iter()
+            .any(|scheme| host.starts_with(scheme))
+        {
+            match &self.tls {
+                Some(dir) => Docker::connect_with_ssl(
+                    host,
+                    &dir.join("key.pem"),
+                    &dir.join("cert.pem"),
+                    &dir.join("ca.pem"),
+                    CONNECT_TIMEOUT_SECS,
+                    version,
+                ),
+                None => Docker::connect_with_http(host, CONNECT_TIMEOUT_SECS, version),
+            }
+        } else {
+            miette::bail!(
+                help = "Use a unix://, npipe:// or tcp:// address. For ssh, forward the remote socket locally",
+                "Unsupported docker host: {}",
+                host
+            )
+        };
+
+        docker
+            .into_diagnostic()
+            .wrap_err_with(|| format!("Failed to connect to {}", host))
+    }
+}
+
+fn env_var(name: &str) -> Option<String> {
+    std::env::var(name).ok().filter(|value| !value.is_empty())
+}
+
+/// `DOCKER_CONFIG`, or `~/.docker`
+fn docker_config_dir() -> Result<PathBuf> {
+    if let Some(dir) = env_var("DOCKER_CONFIG") {
+        return Ok(PathBuf::from(dir));
+    }
+
+    let home = std::env::home_dir().wrap_err("Failed to get HOME environment variable")?;
+    Ok(home.join(".docker"))
+}
+
+fn current_context(config_dir: &Path) -> Result<Option<String>> {
+    let path = config_dir.join("config.json");
+    if !path.exists() {
+        return Ok(None);
+    }
+
+    let content = std::fs::read_to_string(&path)
+        .into_diagnostic()
+        .wrap_err_with(|| format!("Failed to read {}", path.display()))?;
+    let config: DockerConfigFile = serde_json::from_str(&content)
+        .into_diagnostic()
+        .wrap_err_with(|| format!("Failed to parse {}", path.display()))?;
+
+    Ok(config.current_context)
+}
+
+fn context_digest(name: &str) -> String {
+    use sha2::{Digest, Sha256};
+
+    Sha256::digest(name.as_bytes())
+        .iter()
+        .map(|b| format!("{b:02x}"))
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_from_context() {
+        let config_dir = std::env::temp_dir().join(format!("pgd-context-{}", std::process::id()));
+        let digest = context_digest("remote");
+        let meta_dir = config_dir.join("contexts").join("meta").join(&digest);
+        let tls_dir = config_dir
+            .join("contexts")
+            .join("tls")
+            .join(&digest)
+            .join("docker");
+        std::fs::create_dir_all(&meta_dir).unwrap();
+        std::fs::create_dir_all(&tls_dir).unwrap();
+        std::fs::write(
+            meta_dir.join("meta.json"),
+            r#"{"Name":"remote","Metadata":{},"Endpoints":{"docker":{"Host":"tcp://10.0.0.2:2376","SkipTLSVerify":false}}}"#,
+        )
+        .unwrap();
+
+        let endpoint = DockerEndpoint::from_context(&config_dir, "remote").unwrap();
+        let missing = DockerEndpoint::from_context(&config_dir, "missing");
+        std::fs::remove_dir_all(&config_dir).unwrap();
+
+        assert_eq!(endpoint.host, "tcp://10.0.0.2:2376");
+        assert_eq!(endpoint.tls, Some(tls_dir));
+        assert!(missing.is_err());
+    }
+}
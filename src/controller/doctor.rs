@@ -0,0 +1,756 @@
+//! Checks of the environment pgd runs in, and recovery of instances the state file lost track of
+
+use std::{
+    collections::HashMap,
+    path::{Path, PathBuf},
+    str::FromStr,
+    sync::Arc,
+};
+
+use colored::Colorize;
+use miette::{Result, miette};
+
+use crate::{
+    cli::{GlobalOptions, OutputFormat},
+    config::{DataStorage, Limits, PostgresVersion, Project, UserConfig},
+    controller::{
+        Context,
+        docker::{self, DockerController},
+        output::{Check, CheckStatus, DoctorReport, RecoveredInstance, print_json},
+        ports,
+        runtime::{
+            BIND_LABEL, CREATED_AT_LABEL, ContainerInfo, DATA_DIR_LABEL, DaemonInfo, PORT_LABEL,
+            PROJECT_LABEL, SOCKET_DIR_LABEL, TLS_DIR_LABEL, VERSION_LABEL, data_mount_path,
+        },
+    },
+    paths,
+    state::{InstanceState, StateManager},
+};
+
+/// Below this, pulling an image or growing a database is likely to fail
+const MIN_FREE_SPACE: u64 = 1 << 30;
+const LOW_FREE_SPACE: u64 = 5 << 30;
+/// TLS certificates and `created_at` of instances get confusing past this
+const MAX_CLOCK_SKEW_SECS: i64 = 60;
+
+impl Check {
+    fn pass(name: &'static str, detail: impl Into<String>) -> Self {
+        Self {
+            name,
+            status: CheckStatus::Pass,
+            detail: detail.into(),
+            hint: None,
+        }
+    }
+
+    fn warn(name: &'static str, detail: impl Into<String>, hint: impl Into<String>) -> Self {
+        Self {
+            name,
+            status: CheckStatus::Warn,
+            detail: detail.into(),
+            hint: Some(hint.into()),
+        }
+    }
+
+    /// Detail and hint come from the error, its root cause and its help
+    fn fail(name: &'static str, err: &miette::Report) -> Self {
+        let mut diagnostic: &dyn miette::Diagnostic = err.as_ref();
+        while let Some(next) = diagnostic.diagnostic_source() {
+            diagnostic = next;
+        }
+        let mut root: &dyn std::error::Error = diagnostic;
+        while let Some(next) = root.source() {
+            root = next;
+        }
+
+        let message = err.to_string();
+        // parse errors explain themselves in the label instead
+        let root = err
+            .labels()
+            .and_then(|mut labels| labels.find_map(|l| l.label().map(str::to_string)))
+            .unwrap_or_else(|| root.to_string());
+        Self {
+            name,
+            status: CheckStatus::Fail,
+            detail: if root == message {
+                message
+            } else {
+                format!("{message} ({root})")
+            },
+            hint: err.help().map(|help| help.to_string()),
+        }
+    }
+}
+
+/// `pgd doctor`. Checks that don't need the daemon run even when it is unreachable
+pub async fn run(options: &GlobalOptions, repair: bool) -> Result<()> {
+    let mut checks = Vec::new();
+
+    let project = match Project::load() {
+        Ok(Some(project)) => {
+            checks.push(Check::pass(
+                "pgd.toml",
+                format!(
+                    "project {}, postgres {}, listening on {}",
+                    project.name,
+                    project.config.version,
+                    project.listen()
+                ),
+            ));
+            Some(project)
+        }
+        Ok(None) => {
+            checks.push(Check::pass("pgd.toml", "no project in this directory"));
+            None
+        }
+        Err(err) => {
+            checks.push(Check::fail("pgd.toml", &err));
+            None
+        }
+    };
+
+    let user = match UserConfig::load() {
+        Ok(user) => {
+            let path = paths::config_file()?;
+            let detail = match path.is_file() {
+                true => format!("loaded from {}", path.display()),
+                false => format!("defaults, no {}", path.display()),
+            };
+            checks.push(Check::pass("User config", detail));
+            user
+        }
+        Err(err) => {
+            checks.push(Check::fail("User config", &err));
+            UserConfig::default()
+        }
+    };
+
+    let state = match StateManager::new() {
+        Ok(state) => {
+            checks.push(Check::pass(
+                "State file",
+                format!(
+                    "{} instances recorded in {}",
+                    state.instances().len(),
+                    state.path().unwrap_or(Path::new("memory")).display()
+                ),
+            ));
+            Some(state)
+        }
+        Err(err) => {
+            checks.push(Check::fail("State file", &err));
+            None
+        }
+    };
+
+    let kind = docker::resolve_runtime(project.as_ref().and_then(|p| p.config.runtime));
+    let runtime = match kind {
+        Ok(kind) => DockerController::new(kind, options.docker_host.as_deref()).await,
+        Err(err) => Err(err),
+    };
+
+    let mut recoverable = Vec::new();
+    match runtime {
+        Ok(runtime) => {
+            let has_state = state.is_some();
+            let ctx = Context::with_runtime(
+                Arc::new(runtime.with_registry_mirror(user.registry_mirror.clone())),
+                project,
+                None,
+                state.unwrap_or_else(StateManager::in_memory),
+            )
+            .with_options(options.clone())
+            .with_user_config(user);
+
+            let (daemon_checks, found) = daemon_checks(&ctx, has_state, repair).await;
+            checks.extend(daemon_checks);
+            recoverable = found;
+        }
+        Err(err) => checks.push(Check::fail("Daemon", &err)),
+    }
+
+    let total = checks.len();
+    let failed = checks
+        .iter()
+        .filter(|c| c.status == CheckStatus::Fail)
+        .count();
+
+    if options.output == OutputFormat::Json {
+        print_json(&DoctorReport {
+            checks,
+            recoverable: recoverable
+                .iter()
+                .map(|(project, instance)| RecoveredInstance {
+                    project: project.clone(),
+                    container_id: instance.container_id.clone(),
+                    port: instance.port,
+                })
+                .collect(),
+            repaired: repair,
+        })?;
+    } else {
+        print_checks(&checks);
+    }
+
+    if failed > 0 {
+        return Err(miette!("{} of {} checks failed", failed, total));
+    }
+    Ok(())
+}
+
+/// Checks that need the daemon. With `repair`, instances found through container labels are written to state
+pub async fn daemon_checks(
+    ctx: &Context,
+    has_state: bool,
+    repair: bool,
+) -> (Vec<Check>, Vec<(String, InstanceState)>) {
+    let mut checks = Vec::new();
+
+    let info = match ctx.runtime.daemon_info().await {
+        Ok(info) => {
+            checks.push(check_api_version(&info, &ctx.runtime.daemon_host()));
+            Some(info)
+        }
+        Err(err) => {
+            checks.push(Check::fail("Daemon", &err));
+            None
+        }
+    };
+    if let Some(info) = &info {
+        checks.push(check_disk_space(info, &ctx.runtime.daemon_host()));
+        checks.push(check_clock(info.system_time.as_deref(), chrono::Utc::now()));
+    }
+
+    // a failing daemon call fails its check, the others still run
+    let or_fail = |name, check: Result<Check>| check.unwrap_or_else(|err| Check::fail(name, &err));
+
+    checks.push(or_fail("Ports", check_ports(ctx).await));
+
+    let mut recoverable = Vec::new();
+    if has_state {
+        let state = async {
+            let found = self::recoverable(ctx).await?;
+            if repair && !found.is_empty() {
+                for (project, instance) in &found {
+                    ctx.state.upsert(project.clone(), instance.clone());
+                }
+                ctx.state.save()?;
+            }
+            let check = check_consistency(ctx, &found, repair).await?;
+            recoverable = found;
+            Ok(check)
+        }
+        .await;
+        checks.push(or_fail("State", state));
+    }
+
+    checks.push(or_fail("Images", check_images(ctx).await));
+
+    (checks, recoverable)
+}
+
+fn check_api_version(info: &DaemonInfo, host: &str) -> Check {
+    const NAME: &str = "Daemon";
+    let client = bollard::API_DEFAULT_VERSION;
+    let detail = format!("{} (API {}) at {}", info.version, info.api_version, host);
+
+    let daemon = info
+        .api_version
+        .split_once('.')
+        .and_then(|(major, minor)| Some((major.parse().ok()?, minor.parse().ok()?)));
+    match daemon {
+        Some(daemon) if daemon < (client.major_version, client.minor_version) => Check::warn(
+            NAME,
+            detail,
+            format!(
+                "pgd speaks API {}.{}, some commands may fail. Upgrade Docker or Podman",
+                client.major_version, client.minor_version
+            ),
+        ),
+        _ => Check::pass(NAME, detail),
+    }
+}
+
+/// Only measurable when the daemon keeps its data on this machine
+fn check_disk_space(info: &DaemonInfo, host: &str) -> Check {
+    const NAME: &str = "Disk space";
+
+    let is_local = host.starts_with("unix://") || host.starts_with("npipe://");
+    let available = info
+        .root_dir
+        .as_ref()
+        .filter(|_| is_local)
+        .and_then(|dir| Some((dir, fs4::available_space(dir).ok()?)));
+    let Some((dir, available)) = available else {
+        return Check::pass(
+            NAME,
+            "not checked, images and volumes are on another machine",
+        );
+    };
+
+    let detail = format!(
+        "{} free for images and volumes in {}",
+        format_size(available),
+        dir.display()
+    );
+    let hint = "Free up space, e.g. with `docker system prune`";
+    if available < MIN_FREE_SPACE {
+        Check {
+            status: CheckStatus::Fail,
+            hint: Some(hint.to_string()),
+            ..Check::pass(NAME, detail)
+        }
+    } else if available < LOW_FREE_SPACE {
+        Check::warn(NAME, detail, hint)
+    } else {
+        Check::pass(NAME, detail)
+    }
+}
+
+fn check_clock(daemon_time: Option<&str>, now: chrono::DateTime<chrono::Utc>) -> Check {
+    const NAME: &str = "Clock";
+
+    let Some(daemon_time) = daemon_time.and_then(|t| chrono::DateTime::parse_from_rfc3339(t).ok())
+    else {
+        return Check::pass(NAME, "not checked, the daemon did not report its time");
+    };
+
+    let skew = (daemon_time.with_timezone(&chrono::Utc) - now).num_seconds();
+    if skew.abs() <= MAX_CLOCK_SKEW_SECS {
+        return Check::pass(NAME, "in sync with the daemon");
+    }
+
+    let direction = if skew > 0 { "ahead" } else { "behind" };
+    Check::warn(
+        NAME,
+        format!(
+            "daemon clock is {}s {} of this machine",
+            skew.abs(),
+            direction
+        ),
+        "Sync the clock of the machine or VM running the daemon, e.g. restart Docker Desktop after sleep",
+    )
+}
+
+/// Ports of stopped instances and of the current project must be free for them to start
+async fn check_ports(ctx: &Context) -> Result<Check> {
+    const NAME: &str = "Ports";
+    let daemon_host = ctx.runtime.daemon_host();
+
+    let mut ports: Vec<(String, u16)> = Vec::new();
+    let mut problems = Vec::new();
+    for (name, instance) in ctx.state.instances() {
+        let Some(port) = instance.port else {
+            continue;
+        };
+        ports.push((name.clone(), port));
+        if instance
+            .daemon_host
+            .as_ref()
+            .is_some_and(|h| *h != daemon_host)
+        {
+            continue;
+        }
+        let running = ctx
+            .runtime
+            .inspect_container(&instance.container_id)
+            .await?
+            .is_some_and(|c| c.running);
+        if !running && !ports::is_port_available_at(instance.bind_address(), port) {
+            problems.push(format!("port {} of {} is taken", port, name));
+        }
+    }
+
+    if let Some(project) = &ctx.project {
+        let recorded = ctx.state.get(&project.name).and_then(|i| i.port);
+        if let Some(port) = project.config.port
+            && recorded != Some(port)
+        {
+            ports.push((project.name.clone(), port));
+            if !ports::is_port_available_at(project.config.bind_address(), port) {
+                problems.push(format!("port {} of {} is taken", port, project.name));
+            }
+        }
+    }
+
+    let mut by_port: HashMap<u16, Vec<&str>> = HashMap::new();
+    for (name, port) in &ports {
+        let names = by_port.entry(*port).or_default();
+        if !names.contains(&name.as_str()) {
+            names.push(name);
+        }
+    }
+    let mut shared: Vec<_> = by_port.into_iter().filter(|(_, n)| n.len() > 1).collect();
+    shared.sort();
+    for (port, names) in shared {
+        problems.push(format!("{} share port {}", names.join(" and "), port));
+    }
+
+    if problems.is_empty() {
+        return Ok(Check::pass(
+            NAME,
+            format!("{} ports available", ports.len()),
+        ));
+    }
+    Ok(Check::warn(
+        NAME,
+        problems.join(", "),
+        "Stop whatever holds the port, or change port in pgd.toml of the project",
+    ))
+}
+
+async fn check_consistency(
+    ctx: &Context,
+    recoverable: &[(String, InstanceState)],
+    repaired: bool,
+) -> Result<Check> {
+    const NAME: &str = "State";
+    let daemon_host = ctx.runtime.daemon_host();
+
+    let mut missing = Vec::new();
+    let mut other_daemons = 0;
+    for (name, instance) in ctx.state.instances() {
+        if instance
+            .daemon_host
+            .as_ref()
+            .is_some_and(|h| *h != daemon_host)
+        {
+            other_daemons += 1;
+        } else if !recoverable.iter().any(|(r, _)| *r == name)
+            && !ctx
+                .runtime
+                .container_exists_by_id(&instance.container_id)
+                .await?
+        {
+            missing.push(name);
+        }
+    }
+
+    let recovered: Vec<_> = recoverable.iter().map(|(name, _)| name.as_str()).collect();
+    if !recovered.is_empty() && !repaired {
+        return Ok(Check::warn(
+            NAME,
+            format!("{} missing from state", recovered.join(", ")),
+            "Run `pgd doctor --repair` to restore them from container labels",
+        ));
+    }
+    if !missing.is_empty() {
+        return Ok(Check::warn(
+            NAME,
+            format!("containers of {} are gone", missing.join(", ")),
+            "Run `pgd instance start` in the project to recreate it",
+        ));
+    }
+
+    let mut detail = if repaired && !recovered.is_empty() {
+        format!("restored {}", recovered.join(", "))
+    } else {
+        "matches the containers".to_string()
+    };
+    if other_daemons > 0 {
+        detail.push_str(&format!(
+            ", {other_daemons} instances on other daemons not checked"
+        ));
+    }
+    Ok(Check::pass(NAME, detail))
+}
+
+/// Images of versions no instance uses anymore, e.g. after changing version in pgd.toml
+async fn check_images(ctx: &Context) -> Result<Check> {
+    const NAME: &str = "Images";
+
+    let mut in_use: Vec<PostgresVersion> = ctx
+        .state
+        .instances()
+        .into_iter()
+        .map(|(_, i)| i.postgres_version)
+        .collect();
+    in_use.extend(ctx.project.as_ref().map(|p| p.config.version));
+    for (_, info) in ctx.runtime.list_project_containers().await? {
+        in_use.extend(
+            info.labels
+                .get(VERSION_LABEL)
+                .and_then(|v| PostgresVersion::from_str(v).ok()),
+        );
+    }
+
+    let images = ctx.runtime.postgres_images().await?;
+    let mut stale: Vec<_> = images.iter().filter(|(v, _)| !in_use.contains(v)).collect();
+    if stale.is_empty() {
+        return Ok(Check::pass(
+            NAME,
+            format!("{} postgres images, all in use", images.len()),
+        ));
+    }
+
+    stale.sort();
+    let size = stale.iter().map(|(_, size)| size).sum();
+    let tags: Vec<_> = stale.iter().map(|(v, _)| format!("postgres:{v}")).collect();
+    Ok(Check::warn(
+        NAME,
+        format!("{} unused ({})", tags.join(", "), format_size(size)),
+        format!(
+            "Unless something else needs them, remove them with `docker image rm {}`",
+            tags.join(" ")
+        ),
+    ))
+}
+
+fn format_size(bytes: u64) -> String {
+    const GIB: f64 = (1u64 << 30) as f64;
+    const MIB: f64 = (1u64 << 20) as f64;
+
+    let bytes = bytes as f64;
+    if bytes >= GIB {
+        format!("{:.1} GiB", bytes / GIB)
+    } else {
+        format!("{:.0} MiB", bytes / MIB)
+    }
+}
+
+fn print_checks(checks: &[Check]) {
+    for check in checks {
+        let mark = match check.status {
+            CheckStatus::Pass => "✓".green().bold(),
+            CheckStatus::Warn => "⚠".yellow().bold(),
+            CheckStatus::Fail => "✗".red().bold(),
+        };
+        println!(
+            "{} {} {}",
+            mark,
+            format!("{:<12}", check.name).bold(),
+            check.detail
+        );
+        if let Some(hint) = &check.hint {
+            println!("  {} {}", "→".cyan(), hint.cyan());
+        }
+    }
+}
+
+/// Instance described by the labels of its container, `None` for containers
+/// created before pgd stamped them
+pub fn instance_from_labels(
+    container_id: &str,
+    info: &ContainerInfo,
+    daemon_host: String,
+) -> Option<(String, InstanceState)> {
+    let label = |name: &str| info.labels.get(name);
+
+    let project = label(PROJECT_LABEL)?.clone();
+    let version = PostgresVersion::from_str(label(VERSION_LABEL)?).ok()?;
+    // socket-only containers publish no port
+    let port = label(PORT_LABEL).and_then(|port| port.parse().ok());
+    let created_at = label(CREATED_AT_LABEL)?.parse().ok()?;
+    // containers labelled before the address was configurable listen on loopback
+    let bind = label(BIND_LABEL).and_then(|bind| bind.parse().ok());
+
+    let storage = match label(DATA_DIR_LABEL) {
+        Some(dir) => Some(DataStorage::HostDir(PathBuf::from(dir))),
+        None => info
+            .volumes
+            .get(data_mount_path(&version))
+            .cloned()
+            .map(DataStorage::Volume),
+    };
+
+    let mut instance = InstanceState {
+        container_id: container_id.to_string(),
+        postgres_version: version,
+        port,
+        created_at,
+        // unknown, so the next start applies the password of pgd.toml
+        password_fingerprint: None,
+        volume_name: None,
+        data_dir: None,
+        daemon_host: Some(daemon_host),
+        bind,
+        socket_dir: label(SOCKET_DIR_LABEL).map(PathBuf::from),
+        tls_dir: label(TLS_DIR_LABEL).map(PathBuf::from),
+        // unknown, so the next start applies the limits of pgd.toml
+        limits: Limits::default(),
+    };
+    if let Some(storage) = storage {
+        instance.set_storage(storage);
+    }
+
+    Some((project, instance))
+}
+
+/// Instances whose containers exist but are missing from the state file, or
+/// recorded with a container that is gone. The newest container of a project wins
+pub async fn recoverable(ctx: &Context) -> Result<Vec<(String, InstanceState)>> {
+    let daemon_host = ctx.runtime.daemon_host();
+
+    let mut found: HashMap<String, InstanceState> = HashMap::new();
+    for (id, info) in ctx.runtime.list_project_containers().await? {
+        let Some((project, instance)) = instance_from_labels(&id, &info, daemon_host.clone())
+        else {
+            continue;
+        };
+        match found.get(&project) {
+            Some(newer) if newer.created_at >= instance.created_at => {}
+            _ => {
+                found.insert(project, instance);
+            }
+        }
+    }
+
+    let mut recoverable = Vec::new();
+    for (project, instance) in found {
+        if let Some(recorded) = ctx.state.get(&project) {
+            // containers of other daemons can't be checked from here
+            let other_daemon = recorded
+                .daemon_host
+                .as_ref()
+                .is_some_and(|host| *host != daemon_host);
+            if other_daemon
+                || ctx
+                    .runtime
+                    .container_exists_by_id(&recorded.container_id)
+                    .await?
+            {
+                continue;
+            }
+        }
+        recoverable.push((project, instance));
+    }
+
+    recoverable.sort_by(|a, b| a.0.cmp(&b.0));
+    Ok(recoverable)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        config::PGDConfig,
+        controller::{
+            reconciler::Reconciler,
+            runtime::{ContainerRuntime, fake::FakeRuntime},
+        },
+    };
+
+    fn project(name: &str, port: u16, data_dir: Option<&str>) -> Project {
+        Project {
+            name: name.to_string(),
+            path: PathBuf::from(format!("/home/user/{name}")),
+            config: PGDConfig {
+                data_dir: data_dir.map(PathBuf::from),
+                ..PGDConfig::for_test("17.7", port)
+            },
+        }
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_recovers_lost_state_from_labels() {
+        let runtime = FakeRuntime::new();
+        let dir = std::env::temp_dir().join(format!("pgd-doctor-{}", std::process::id()));
+        let mut api = project("api", 5433, Some("data"));
+        api.path = dir.clone();
+        let projects = [project("app", 5432, None), api];
+        let mut lost = Vec::new();
+        for project in &projects {
+            let ctx = Context::with_runtime(
+                Arc::new(runtime.clone()),
+                Some(project.clone()),
+                None,
+                StateManager::in_memory(),
+            );
+            Reconciler { ctx: &ctx }.reconcile(project).await.unwrap();
+            lost.push(ctx.state().get(&project.name).unwrap());
+        }
+        let _ = std::fs::remove_dir_all(&dir);
+
+        let ctx = Context::with_runtime(
+            Arc::new(runtime.clone()),
+            None,
+            None,
+            StateManager::in_memory(),
+        );
+        let recovered = recoverable(&ctx).await.unwrap();
+
+        assert_eq!(recovered.len(), 2);
+        let (name, api) = &recovered[0];
+        assert_eq!(name, "api");
+        assert_eq!(api.container_id, lost[1].container_id);
+        assert_eq!(api.port, Some(5433));
+        assert_eq!(api.created_at, lost[1].created_at);
+        assert_eq!(api.data_dir, Some(dir.join("data")));
+        let (name, app) = &recovered[1];
+        assert_eq!(name, "app");
+        assert_eq!(app.volume_name.as_deref(), Some("pgd-app-data"));
+        assert_eq!(app.daemon_host.as_deref(), Some("fake://local"));
+
+        ctx.state().upsert("app".to_string(), lost[0].clone());
+        let recovered = recoverable(&ctx).await.unwrap();
+        assert_eq!(recovered.len(), 1);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_flags_missing_containers_and_stale_images() {
+        let runtime = FakeRuntime::new();
+        let app = project("app", ports::random_free_port().unwrap(), None);
+        let ctx = Context::with_runtime(
+            Arc::new(runtime.clone()),
+            Some(app.clone()),
+            None,
+            StateManager::in_memory(),
+        );
+        Reconciler { ctx: &ctx }.reconcile(&app).await.unwrap();
+        let mut gone = ctx.state().get("app").unwrap();
+        gone.container_id = "removed".to_string();
+        gone.port = Some(ports::random_free_port().unwrap());
+        ctx.state().upsert("api".to_string(), gone);
+        runtime
+            .ensure_version_downloaded(&"16.11".parse().unwrap())
+            .await
+            .unwrap();
+
+        let (checks, recoverable) = daemon_checks(&ctx, true, false).await;
+
+        let check = |name| checks.iter().find(|c| c.name == name).unwrap();
+        assert!(recoverable.is_empty());
+        assert_eq!(check("Daemon").status, CheckStatus::Pass);
+        assert_eq!(check("Ports").status, CheckStatus::Pass);
+        assert_eq!(check("State").status, CheckStatus::Warn);
+        assert!(check("State").detail.contains("api"));
+        assert_eq!(check("Images").status, CheckStatus::Warn);
+        assert!(check("Images").detail.contains("postgres:16.11"));
+        assert!(!check("Images").detail.contains("postgres:17.7"));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_failing_check_keeps_the_others() {
+        let runtime = FakeRuntime::new();
+        runtime.fail_listing();
+        let ctx = Context::with_runtime(
+            Arc::new(runtime.clone()),
+            None,
+            None,
+            StateManager::in_memory(),
+        );
+
+        let (checks, _) = daemon_checks(&ctx, true, false).await;
+
+        let check = |name| checks.iter().find(|c| c.name == name).unwrap();
+        assert_eq!(check("State").status, CheckStatus::Fail);
+        assert!(check("State").detail.contains("request timed out"));
+        assert_eq!(check("Ports").status, CheckStatus::Pass);
+        assert_eq!(check("Images").status, CheckStatus::Fail);
+    }
+
+    #[test]
+    fn test_check_clock() {
+        let now = chrono::DateTime::parse_from_rfc3339("2025-06-01T12:00:00Z")
+            .unwrap()
+            .with_timezone(&chrono::Utc);
+
+        let synced = check_clock(Some("2025-06-01T12:00:05.123456789Z"), now);
+        let behind = check_clock(Some("2025-06-01T11:55:00+00:00"), now);
+
+        assert_eq!(synced.status, CheckStatus::Pass);
+        assert_eq!(behind.status, CheckStatus::Warn);
+        assert!(behind.detail.contains("300s behind"));
+        assert_eq!(check_clock(None, now).status, CheckStatus::Pass);
+    }
+}
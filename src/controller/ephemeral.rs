@@ -0,0 +1,243 @@
+use std::sync::Arc;
+
+use miette::{IntoDiagnostic, Result};
+use tracing::warn;
+
+use crate::{
+    config::{LOOPBACK, PostgresVersion},
+    consts::{DATABASE, USERNAME},
+    controller::{ports, runtime::ContainerRuntime, utils},
+};
+
+const READY_TIMEOUT_SECS: u64 = 60;
+
+/// Throwaway instance with PGDATA on tmpfs, never recorded in the state file.
+///
+/// Call [`EphemeralInstance::cleanup`] when done. Dropping it without cleaning up
+/// removes the container on a background thread as a last resort, which a process
+/// exiting right after may cut short.
+pub struct EphemeralInstance {
+    runtime: Arc<dyn ContainerRuntime>,
+    container_id: String,
+    version: PostgresVersion,
+    password: String,
+    port: u16,
+    removed: bool,
+}
+
+impl EphemeralInstance {
+    /// Create the container without starting it. `version` is either a major (`16`)
+    /// or an exact (`16.11`) version, the latest one by default
+    pub async fn create(runtime: Arc<dyn ContainerRuntime>, version: Option<&str>) -> Result<Self> {
+        Self::create_as(runtime, version, &Self::container_name()).await
+    }
+
+    /// Random, so several ephemeral instances can run side by side
+    pub fn container_name() -> String {
+        format!("pgd-ephemeral-{}", utils::random_suffix())
+    }
+
+    /// Like [`Self::create`], with a name known up front, so a caller giving up midway
+    /// can still find the container with [`Self::remove_unfinished`]
+    pub async fn create_as(
+        runtime: Arc<dyn ContainerRuntime>,
+        version: Option<&str>,
+        container_name: &str,
+    ) -> Result<Self> {
+        let available = runtime.available_versions().await?;
+        let version = utils::resolve_version(version, &available)?;
+
+        runtime.ensure_version_downloaded(&version).await?;
+
+        let password = utils::generate_password();
+        let port = ports::random_free_port()?;
+
+        let container_id = runtime
+            .create_ephemeral_container(container_name, &version, &password, port)
+            .await?;
+
+        Ok(Self {
+            runtime,
+            container_id,
+            version,
+            password,
+            port,
+            removed: false,
+        })
+    }
+
+    /// Start the container and wait until it accepts connections
+    pub async fn start(&self) -> Result<()> {
+        self.runtime.start_container(&self.container_id).await?;
+        self.runtime
+            .wait_until_ready(&self.container_id, READY_TIMEOUT_SECS)
+            .await
+    }
+
+    pub fn version(&self) -> PostgresVersion {
+        self.version
+    }
+
+    pub fn port(&self) -> u16 {
+        self.port
+    }
+
+    pub fn password(&self) -> &str {
+        &self.password
+    }
+
+    pub fn dsn(&self) -> String {
+        utils::format_dsn(LOOPBACK, &self.password, self.port)
+    }
+
+    /// `DATABASE_URL` along with the variables libpq reads
+    pub fn env(&self) -> Vec<(&'static str, String)> {
+        vec![
+            ("DATABASE_URL", self.dsn()),
+            ("PGHOST", "127.0.0.1".to_string()),
+            ("PGPORT", self.port.to_string()),
+            ("PGUSER", USERNAME.to_string()),
+            ("PGPASSWORD", self.password.clone()),
+            ("PGDATABASE", DATABASE.to_string()),
+        ]
+    }
+
+    /// Remove the container a cancelled [`Self::create_as`] may have left behind, if any
+    pub async fn remove_unfinished(
+        runtime: &dyn ContainerRuntime,
+        container_name: &str,
+    ) -> Result<()> {
+        if runtime.inspect_container(container_name).await?.is_some() {
+            runtime.remove_container(container_name, true, true).await?;
+        }
+        Ok(())
+    }
+
+    /// Remove the container along with its data
+    pub async fn cleanup(mut self) -> Result<()> {
+        self.removed = true;
+        self.runtime
+            .remove_container(&self.container_id, true, true)
+            .await
+    }
+}
+
+impl Drop for EphemeralInstance {
+    fn drop(&mut self) {
+        if self.removed {
+            return;
+        }
+
+        // the runtime of the caller may be shutting down, so the thread brings its own,
+        // and isn't waited for to not block the caller
+        let container_id = std::mem::take(&mut self.container_id);
+        let container_runtime = self.runtime.clone();
+        std::thread::spawn(move || {
+            let removed = tokio::runtime::Builder::new_current_thread()
+                .enable_all()
+                .build()
+                .into_diagnostic()
+                .and_then(|runtime| {
+                    runtime.block_on(async {
+                        container_runtime
+                            .reconnect()
+                            .await?
+                            .remove_container(&container_id, true, true)
+                            .await
+                    })
+                });
+            if let Err(err) = removed {
+                warn!("Failed to remove ephemeral container {container_id}: {err}");
+            }
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::controller::runtime::fake::FakeRuntime;
+
+    #[tokio::test(start_paused = true)]
+    async fn test_cleanup_removes_container() {
+        let runtime = FakeRuntime::new();
+
+        let instance = EphemeralInstance::create(Arc::new(runtime.clone()), Some("16"))
+            .await
+            .unwrap();
+        instance.start().await.unwrap();
+        let container = runtime.container(&instance.container_id).unwrap();
+        instance.cleanup().await.unwrap();
+
+        assert!(container.running);
+        assert!(container.ephemeral);
+        assert_eq!(container.version, "16.11".parse().unwrap());
+        assert!(container.volumes.is_empty());
+        assert!(runtime.containers().is_empty());
+        assert!(runtime.volumes().is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_drop_removes_container() {
+        let runtime = FakeRuntime::new();
+
+        let instance = EphemeralInstance::create(Arc::new(runtime.clone()), None)
+            .await
+            .unwrap();
+        let created = runtime.containers().len();
+        drop(instance);
+        // removed on a thread of its own
+        for _ in 0..100 {
+            if runtime.containers().is_empty() {
+                break;
+            }
+            std::thread::sleep(std::time::Duration::from_millis(10));
+        }
+
+        assert_eq!(created, 1);
+        assert!(runtime.containers().is_empty());
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_env_points_to_instance() {
+        let runtime = FakeRuntime::new();
+
+        let instance = EphemeralInstance::create(Arc::new(runtime.clone()), None)
+            .await
+            .unwrap();
+        let env: std::collections::HashMap<_, _> = instance.env().into_iter().collect();
+        let port = instance.port();
+        let container = runtime.container(&instance.container_id).unwrap();
+        instance.cleanup().await.unwrap();
+
+        assert_eq!(container.port, Some(port));
+        assert_eq!(
+            env["DATABASE_URL"],
+            format!(
+                "postgres://postgres:{}@127.0.0.1:{port}/postgres",
+                container.password
+            )
+        );
+        assert_eq!(env["PGPORT"], port.to_string());
+        assert_eq!(env["PGPASSWORD"], container.password);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_removes_unfinished_container_by_name() {
+        let runtime = FakeRuntime::new();
+        let name = EphemeralInstance::container_name();
+        runtime
+            .create_ephemeral_container(&name, &"17.7".parse().unwrap(), "secret", 5432)
+            .await
+            .unwrap();
+
+        EphemeralInstance::remove_unfinished(&runtime, &name)
+            .await
+            .unwrap();
+        EphemeralInstance::remove_unfinished(&runtime, &name)
+            .await
+            .unwrap();
+
+        assert!(runtime.containers().is_empty());
+    }
+}
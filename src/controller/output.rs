@@ -0,0 +1,213 @@
+//! Results of commands as printed by `--output json`. Field names are part of the interface
+
+use std::path::PathBuf;
+
+use miette::{IntoDiagnostic, Result};
+use serde::Serialize;
+
+use crate::controller::reconciler::Action;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum InstanceStatus {
+    Running,
+    Stopped,
+    ContainerMissing,
+    NotInitialized,
+    /// Created through another docker daemon than the current one
+    OtherDaemon,
+}
+
+/// Setting that differs between pgd.toml and the instance
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum DriftField {
+    Storage,
+    Version,
+    Port,
+    Bind,
+    SocketDir,
+    Memory,
+    Cpus,
+    ShmSize,
+    Tls,
+    Password,
+}
+
+impl DriftField {
+    /// How the status table names it
+    pub fn label(self) -> &'static str {
+        match self {
+            DriftField::Storage => "Storage",
+            DriftField::Version => "Version",
+            DriftField::Port => "Port",
+            DriftField::Bind => "Bind address",
+            DriftField::SocketDir => "Socket directory",
+            DriftField::Memory => "Memory",
+            DriftField::Cpus => "CPUs",
+            DriftField::ShmSize => "Shared memory",
+            DriftField::Tls => "TLS",
+            DriftField::Password => "Password",
+        }
+    }
+}
+
+/// Difference between pgd.toml and the instance
+#[derive(Debug, Serialize)]
+pub struct Drift {
+    pub field: DriftField,
+    /// Omitted for the password
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub config: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub container: Option<String>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct StatusReport {
+    pub project: String,
+    pub container_name: String,
+    pub status: InstanceStatus,
+    pub container_id: Option<String>,
+    pub postgres_version: Option<String>,
+    pub port: Option<u16>,
+    pub data: Option<String>,
+    pub daemon_host: Option<String>,
+    pub drift: Vec<Drift>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct ConnectionInfo {
+    pub project: String,
+    pub postgres_version: String,
+    /// `None` for instances reached only through their socket
+    pub host: Option<String>,
+    pub port: Option<u16>,
+    pub socket_dir: Option<PathBuf>,
+    /// CA certificate to verify the instance against, when it serves TLS
+    pub root_cert: Option<PathBuf>,
+    pub username: &'static str,
+    pub password: String,
+    pub database: &'static str,
+    /// Over TCP when the instance publishes a port, over the socket otherwise
+    pub dsn: String,
+    pub socket_dsn: Option<String>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct InitReport {
+    pub project: String,
+    pub path: PathBuf,
+    pub postgres_version: String,
+    pub port: Option<u16>,
+    /// `false` when pgd.toml already existed
+    pub created: bool,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Outcome {
+    Done,
+    /// Nothing to do, e.g. stopping a stopped container
+    Unchanged,
+    Cancelled,
+}
+
+/// Result of a lifecycle command
+#[derive(Debug, Serialize)]
+pub struct ActionReport {
+    pub action: &'static str,
+    pub project: String,
+    pub container_name: String,
+    pub outcome: Outcome,
+}
+
+/// Actions `start` would take, in order. Empty when the instance is up to date
+#[derive(Debug, Serialize)]
+pub struct PlanReport {
+    pub project: String,
+    pub container_name: String,
+    pub actions: Vec<Action>,
+}
+
+/// Instance rebuilt from the labels of its container
+#[derive(Debug, Serialize)]
+pub struct RecoveredInstance {
+    pub project: String,
+    pub container_id: String,
+    pub port: Option<u16>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum CheckStatus {
+    Pass,
+    Warn,
+    Fail,
+}
+
+/// One line of the `doctor` checklist
+#[derive(Debug, Serialize)]
+pub struct Check {
+    pub name: &'static str,
+    pub status: CheckStatus,
+    pub detail: String,
+    /// How to fix a warning or failure
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub hint: Option<String>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct DoctorReport {
+    pub checks: Vec<Check>,
+    /// Instances missing from state, found through the labels of their containers
+    pub recoverable: Vec<RecoveredInstance>,
+    /// `false` when the instances were only found, not written to state
+    pub repaired: bool,
+}
+
+/// `logs` prints one of these per line
+#[derive(Debug, Serialize)]
+pub struct LogLine<'a> {
+    pub line: &'a str,
+}
+
+pub fn print_json<T: Serialize>(value: &T) -> Result<()> {
+    println!("{}", serde_json::to_string(value).into_diagnostic()?);
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_action_report_fields() {
+        let report = ActionReport {
+            action: "stop",
+            project: "app".to_string(),
+            container_name: "pgd-app".to_string(),
+            outcome: Outcome::Unchanged,
+        };
+
+        assert_eq!(
+            serde_json::to_string(&report).unwrap(),
+            r#"{"action":"stop","project":"app","container_name":"pgd-app","outcome":"unchanged"}"#
+        );
+    }
+
+    #[test]
+    fn test_drift_fields() {
+        let drift = Drift {
+            field: DriftField::ShmSize,
+            config: Some("256m".to_string()),
+            container: None,
+        };
+
+        assert_eq!(
+            serde_json::to_string(&drift).unwrap(),
+            r#"{"field":"shm_size","config":"256m"}"#
+        );
+        assert_eq!(DriftField::SocketDir.label(), "Socket directory");
+    }
+}
@@ -0,0 +1,208 @@
+//! Host ports and addresses of instances
+
+use std::{
+    io::ErrorKind,
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr, TcpListener, UdpSocket},
+    ops::RangeInclusive,
+};
+
+use miette::{Context, IntoDiagnostic, Result, miette};
+
+use crate::{config::UserConfig, state::StateManager};
+
+const DEFAULT_POSTGRES_PORT: u16 = 5432;
+const PORT_SEARCH_RANGE: u16 = 100;
+
+/// Free on both loopbacks, so `localhost` can't resolve to another server over IPv6
+pub fn is_port_available(port: u16) -> bool {
+    let v4 = TcpListener::bind((Ipv4Addr::LOCALHOST, port)).is_ok();
+    let v6 = match TcpListener::bind((Ipv6Addr::LOCALHOST, port)) {
+        Ok(_) => true,
+        // anything else means there is no IPv6 to conflict on
+        Err(err) => err.kind() != ErrorKind::AddrInUse,
+    };
+    v4 && v6
+}
+
+/// Free on both loopbacks, and on `bind` when listening beyond them
+pub fn is_port_available_at(bind: IpAddr, port: u16) -> bool {
+    is_port_available(port) && (bind.is_loopback() || TcpListener::bind((bind, port)).is_ok())
+}
+
+/// Address clients connect to for an instance listening on `bind`. For all
+/// interfaces, the one other machines on the network reach this one through
+pub fn connect_host(bind: IpAddr) -> IpAddr {
+    // connecting a UDP socket only picks the route, nothing is sent
+    let outbound = |local: IpAddr, remote: IpAddr| {
+        let socket = UdpSocket::bind((local, 0)).ok()?;
+        socket.connect((remote, 9)).ok()?;
+        Some(socket.local_addr().ok()?.ip())
+    };
+
+    match bind {
+        IpAddr::V4(ip) if ip.is_unspecified() => {
+            outbound(bind, Ipv4Addr::new(192, 0, 2, 1).into()).unwrap_or(Ipv4Addr::LOCALHOST.into())
+        }
+        IpAddr::V6(ip) if ip.is_unspecified() => {
+            outbound(bind, Ipv6Addr::new(0x2001, 0xdb8, 0, 0, 0, 0, 0, 1).into())
+                .unwrap_or(Ipv6Addr::LOCALHOST.into())
+        }
+        _ => bind,
+    }
+}
+
+/// `host:port`, with IPv6 hosts in brackets
+pub fn format_host_port(host: IpAddr, port: u16) -> String {
+    SocketAddr::new(host, port).to_string()
+}
+
+/// Any free port picked by the OS, for instances that don't need a stable one
+pub fn random_free_port() -> Result<u16> {
+    let listener = TcpListener::bind((Ipv4Addr::LOCALHOST, 0))
+        .into_diagnostic()
+        .wrap_err("Failed to find a free port")?;
+    let port = listener
+        .local_addr()
+        .into_diagnostic()
+        .wrap_err("Failed to find a free port")?
+        .port();
+    Ok(port)
+}
+
+/// Daemons report a taken host port only once the container starts
+pub fn is_bind_conflict(err: &miette::Report) -> bool {
+    const MARKERS: [&str; 3] = [
+        "port is already allocated",
+        "address already in use",
+        "address in use",
+    ];
+
+    err.chain().any(|cause| {
+        let message = cause.to_string().to_lowercase();
+        MARKERS.iter().any(|marker| message.contains(marker))
+    })
+}
+
+/// Hands out ports of the configured range to new projects
+pub struct PortAllocator<'a> {
+    state: &'a StateManager,
+    range: RangeInclusive<u16>,
+}
+
+impl<'a> PortAllocator<'a> {
+    /// `port_range_start` to `port_range_end` of the user config, 5432-5531 by default
+    pub fn new(state: &'a StateManager, user: &UserConfig) -> Result<Self> {
+        let start = user.port_range_start.unwrap_or(DEFAULT_POSTGRES_PORT);
+        let end = user
+            .port_range_end
+            .unwrap_or_else(|| start.saturating_add(PORT_SEARCH_RANGE - 1));
+
+        if end < start {
+            miette::bail!(
+                help = "Set port_range_end to a port after port_range_start in the user config",
+                "Port range {}-{} is empty",
+                start,
+                end
+            );
+        }
+
+        Ok(Self {
+            state,
+            range: start..=end,
+        })
+    }
+
+    /// Reserves the first port of the range for `project` that no other instance owns,
+    /// stopped ones included, and nothing listens on. `avoid` are ports that turned out
+    /// to be taken anyway
+    pub fn allocate(&self, project: &str, avoid: &[u16]) -> Result<u16> {
+        let (start, end) = (*self.range.start(), *self.range.end());
+
+        self.state.reserve_port(project, |taken| {
+            self.range
+                .clone()
+                .find(|port| {
+                    !taken.contains(port) && !avoid.contains(port) && is_port_available(*port)
+                })
+                .ok_or_else(|| {
+                    let owned = taken.iter().filter(|p| self.range.contains(p)).count();
+                    miette!(
+                        help = format!(
+                            "{owned} of them belong to pgd instances. Widen the range with port_range_start and port_range_end in the user config"
+                        ),
+                        "No available ports found in range {}-{}",
+                        start,
+                        end
+                    )
+                })
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_allocator_skips_ports_of_stopped_instances() {
+        let taken = TcpListener::bind((Ipv4Addr::LOCALHOST, 0)).unwrap();
+        let start = taken.local_addr().unwrap().port();
+        let state = StateManager::in_memory();
+        let user = UserConfig {
+            port_range_start: Some(start),
+            port_range_end: Some(start.saturating_add(20)),
+            ..Default::default()
+        };
+        let allocator = PortAllocator::new(&state, &user).unwrap();
+
+        let first = allocator.allocate("app", &[]).unwrap();
+        let second = allocator.allocate("api", &[first]).unwrap();
+        let third = allocator.allocate("web", &[]).unwrap();
+
+        assert!(first > start);
+        assert!(second > first);
+        assert!(third > second);
+        assert_eq!(allocator.allocate("app", &[]).unwrap(), first);
+
+        let empty = UserConfig {
+            port_range_start: Some(start),
+            port_range_end: Some(start),
+            ..Default::default()
+        };
+        let err = PortAllocator::new(&state, &empty)
+            .unwrap()
+            .allocate("other", &[])
+            .unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            format!("No available ports found in range {start}-{start}")
+        );
+    }
+
+    #[test]
+    fn test_connect_host() {
+        let lan: IpAddr = "192.168.1.20".parse().unwrap();
+        assert_eq!(connect_host(lan), lan);
+        assert_eq!(
+            connect_host(Ipv6Addr::LOCALHOST.into()),
+            Ipv6Addr::LOCALHOST
+        );
+        assert!(!connect_host(Ipv4Addr::UNSPECIFIED.into()).is_unspecified());
+        assert_eq!(
+            format_host_port(Ipv6Addr::LOCALHOST.into(), 5432),
+            "[::1]:5432"
+        );
+    }
+
+    #[test]
+    fn test_bind_conflict() {
+        let docker = miette!(
+            "driver failed programming external connectivity on endpoint pgd-app: Bind for 127.0.0.1:5432 failed: port is already allocated"
+        );
+        let podman =
+            miette!("rootlessport listen tcp 127.0.0.1:5432: bind: address already in use");
+        assert!(is_bind_conflict(&docker));
+        assert!(is_bind_conflict(&podman));
+        assert!(!is_bind_conflict(&miette!("No such container: pgd-app")));
+    }
+}
@@ -1,30 +1,153 @@
-use std::time::Duration;
+use std::{fmt::Display, net::IpAddr, path::PathBuf, time::Duration};
 
-use miette::{Diagnostic, bail};
+use miette::{bail, miette};
 
 use colored::Colorize;
+use futures::StreamExt;
 use miette::Result;
-use thiserror::Error;
+use serde::Serialize;
+use serde_with::{DisplayFromStr, serde_as};
 use tracing::info;
 
 use crate::{
-    config::{PostgresVersion, Project},
+    config::{DataStorage, LOOPBACK, Limits, Listen, PostgresVersion, Project},
     controller::{
-        Context,
-        docker::{self},
+        Context, crash,
+        ports::{self, PortAllocator},
+        runtime::ContainerOwner,
+        tls, utils,
     },
+    error::Error,
     state::InstanceState,
 };
 
 const MAX_RETRIES: usize = 10;
+/// Ports tried after the first one of a new project turned out to be taken
+const BIND_RETRIES: usize = 5;
 const VERIFY_DURATION_SECS: u64 = 5;
+const READY_TIMEOUT_SECS: u64 = 30;
 
-#[derive(Error, Debug, Diagnostic)]
-#[error("Failed to sync container state")]
-#[diagnostic(code(pgd::reconcile))]
-pub enum ReconcileError {
-    AlreadyRunning,
-    ImageDownload(#[source] docker::Error),
+/// A step `reconcile` would take, as listed by `pgd plan`
+#[serde_as]
+#[derive(Debug, Clone, PartialEq, Serialize)]
+#[serde(tag = "action", rename_all = "snake_case")]
+pub enum Action {
+    PullImage {
+        #[serde_as(as = "DisplayFromStr")]
+        version: PostgresVersion,
+    },
+    CreateVolume {
+        name: String,
+    },
+    CreateDataDir {
+        path: PathBuf,
+    },
+    CreateContainer {
+        name: String,
+        #[serde_as(as = "DisplayFromStr")]
+        version: PostgresVersion,
+        port: Option<u16>,
+    },
+    /// Port bindings are fixed at creation
+    RecreateForPort {
+        name: String,
+        from: Option<u16>,
+        to: Option<u16>,
+    },
+    /// Like ports, the host address is fixed at creation
+    RecreateForBind {
+        name: String,
+        from: IpAddr,
+        to: IpAddr,
+    },
+    /// And so are mounts
+    RecreateForSocket {
+        name: String,
+        from: Option<PathBuf>,
+        to: Option<PathBuf>,
+    },
+    /// Including the command postgres runs with
+    RecreateForTls {
+        name: String,
+        enabled: bool,
+    },
+    /// Docker can update memory and CPUs in place, but not `/dev/shm`
+    RecreateForLimits {
+        name: String,
+        from: Limits,
+        to: Limits,
+    },
+    Start {
+        name: String,
+    },
+    ApplyPassword {
+        name: String,
+    },
+}
+
+impl Display for Action {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Action::PullImage { version } => write!(f, "Pull postgres {version} image"),
+            Action::CreateVolume { name } => write!(f, "Create volume {name}"),
+            Action::CreateDataDir { path } => {
+                write!(f, "Create data directory {}", path.display())
+            }
+            Action::CreateContainer {
+                name,
+                version,
+                port,
+            } => write!(
+                f,
+                "Create container {name} (postgres {version}, {})",
+                describe_port(*port)
+            ),
+            Action::RecreateForPort { name, from, to } => {
+                write!(
+                    f,
+                    "Recreate container {name} to move it from {} to {}",
+                    describe_port(*from),
+                    describe_port(*to)
+                )
+            }
+            Action::RecreateForBind { name, from, to } => {
+                write!(
+                    f,
+                    "Recreate container {name} to listen on {to} instead of {from}"
+                )
+            }
+            Action::RecreateForSocket { name, to, .. } => match to {
+                Some(dir) => write!(
+                    f,
+                    "Recreate container {name} to mount its socket to {}",
+                    dir.display()
+                ),
+                None => write!(f, "Recreate container {name} to unmount its socket"),
+            },
+            Action::RecreateForTls { name, enabled } => {
+                let verb = if *enabled { "serve" } else { "stop serving" };
+                write!(f, "Recreate container {name} to {verb} TLS")
+            }
+            Action::RecreateForLimits { name, from, to } => {
+                write!(f, "Recreate container {name} to change {from} to {to}")
+            }
+            Action::Start { name } => write!(f, "Start container {name}"),
+            Action::ApplyPassword { name } => write!(f, "Apply password from pgd.toml to {name}"),
+        }
+    }
+}
+
+fn describe_port(port: Option<u16>) -> String {
+    match port {
+        Some(port) => format!("port {port}"),
+        None => "no TCP port".to_string(),
+    }
+}
+
+/// Fingerprint of the password the data of a newly created container has. Kept data was
+/// initialized with whatever password it had back then, so it is unknown and gets applied
+fn applied_fingerprint(project: &Project, is_new_storage: bool) -> Option<String> {
+    is_new_storage.then(|| utils::password_fingerprint(&project.config.password))
 }
 
 pub struct Reconciler<'a> {
@@ -32,18 +155,187 @@ pub struct Reconciler<'a> {
 }
 
 impl<'a> Reconciler<'a> {
+    /// Actions `reconcile` would take, without taking any of them. Fails where
+    /// `reconcile` would refuse, e.g. on a version mismatch
+    pub async fn plan(&self, project: &Project) -> Result<Vec<Action>> {
+        let runtime = &self.ctx.runtime;
+        let name = project.container_name();
+        let mut actions = Vec::new();
+
+        if !runtime.has_image(&project.config.version).await? {
+            actions.push(Action::PullImage {
+                version: project.config.version,
+            });
+        }
+
+        let existing = match &self.ctx.instance {
+            Some(instance) => {
+                self.ctx.ensure_same_daemon(instance)?;
+                self.ensure_container_exists(instance).await?
+            }
+            None => None,
+        };
+
+        let Some(container_id) = existing else {
+            let is_new_storage = match project.data_storage() {
+                DataStorage::Volume(volume) => {
+                    let exists = runtime.volume_exists(&volume).await?;
+                    if !exists {
+                        actions.push(Action::CreateVolume { name: volume });
+                    }
+                    !exists
+                }
+                DataStorage::HostDir(dir) => {
+                    if !dir.exists() {
+                        actions.push(Action::CreateDataDir { path: dir.clone() });
+                    }
+                    utils::is_dir_empty(&dir)?
+                }
+            };
+
+            actions.push(Action::CreateContainer {
+                name: name.clone(),
+                version: project.config.version,
+                port: project.config.port,
+            });
+            actions.push(Action::Start { name: name.clone() });
+            if applied_fingerprint(project, is_new_storage).is_none() {
+                actions.push(Action::ApplyPassword { name });
+            }
+            return Ok(actions);
+        };
+
+        let container_version = runtime
+            .get_container_postgres_version(&container_id)
+            .await?;
+        self.ensure_matches_project_version(project, &container_id, container_version)
+            .await?;
+        self.ensure_matches_project_storage(project)?;
+
+        let instance = self.ctx.state.get(&project.name);
+        let recorded = instance.as_ref().map(|i| (i.listen(), i.limits));
+        let listen = self.ctx.listen(project)?;
+        let limits = project.config.limits();
+        match recorded {
+            Some((from, _)) if from.tcp.map(|a| a.port()) != project.config.port => {
+                self.ensure_port_available(project)?;
+                actions.push(Action::RecreateForPort {
+                    name: name.clone(),
+                    from: from.tcp.map(|a| a.port()),
+                    to: project.config.port,
+                });
+                actions.push(Action::Start { name: name.clone() });
+            }
+            Some((from, _)) if from.tcp != listen.tcp => {
+                actions.push(Action::RecreateForBind {
+                    name: name.clone(),
+                    from: from.tcp.map_or(LOOPBACK, |a| a.ip()),
+                    to: project.config.bind_address(),
+                });
+                actions.push(Action::Start { name: name.clone() });
+            }
+            Some((from, _)) if from.socket_dir != listen.socket_dir => {
+                actions.push(Action::RecreateForSocket {
+                    name: name.clone(),
+                    from: from.socket_dir,
+                    to: listen.socket_dir,
+                });
+                actions.push(Action::Start { name: name.clone() });
+            }
+            Some((from, _)) if from.tls != listen.tls => {
+                actions.push(Action::RecreateForTls {
+                    name: name.clone(),
+                    enabled: listen.tls.is_some(),
+                });
+                actions.push(Action::Start { name: name.clone() });
+            }
+            Some((_, from)) if from != limits => {
+                actions.push(Action::RecreateForLimits {
+                    name: name.clone(),
+                    from,
+                    to: limits,
+                });
+                actions.push(Action::Start { name: name.clone() });
+            }
+            _ => {
+                if !runtime.is_container_running_by_id(&container_id).await? {
+                    actions.push(Action::Start { name: name.clone() });
+                }
+            }
+        }
+
+        let fingerprint = utils::password_fingerprint(&project.config.password);
+        if let Some(instance) = instance
+            && instance.password_fingerprint.as_deref() != Some(fingerprint.as_str())
+        {
+            actions.push(Action::ApplyPassword { name });
+        }
+
+        Ok(actions)
+    }
+
     pub async fn reconcile(&self, project: &Project) -> Result<()> {
         self.ctx
-            .docker
+            .runtime
             .ensure_version_downloaded(&project.config.version)
             .await?;
 
-        self.ensure_container_running(project).await?;
+        let container_id = self.ensure_container_running(project).await?;
+        self.ensure_password_applied(project, &container_id).await?;
 
         Ok(())
     }
 
-    async fn ensure_container_running(&self, project: &Project) -> Result<()> {
+    /// Reconciles a project whose port pgd picked itself, moving it to the next free
+    /// one while its container fails to bind, e.g. when another program took the
+    /// port in the meantime. The caller writes the final port to pgd.toml
+    pub async fn reconcile_on_free_port(
+        &self,
+        project: &mut Project,
+        allocator: &PortAllocator<'_>,
+    ) -> Result<()> {
+        let mut conflicts = Vec::new();
+
+        loop {
+            let err = match self.reconcile(project).await {
+                Ok(()) => return Ok(()),
+                Err(err) => err,
+            };
+            let taken = matches!(err.downcast_ref(), Some(Error::PortTaken { .. }));
+            let Some(from) = project.config.port.filter(|_| taken) else {
+                return Err(err);
+            };
+            if conflicts.len() == BIND_RETRIES {
+                return Err(err);
+            }
+
+            // port bindings are fixed at creation
+            if let Some(instance) = self.ctx.state.remove(&project.name) {
+                self.ctx
+                    .runtime
+                    .remove_container(&instance.container_id, false, false)
+                    .await?;
+                self.ctx.state.save()?;
+            }
+
+            conflicts.push(from);
+            let port = allocator.allocate(&project.name, &conflicts)?;
+            self.ctx.progress(format!(
+                "{} {} {} {}",
+                "Port".yellow(),
+                from.to_string().yellow(),
+                "is taken, moving to".yellow(),
+                port.to_string().yellow()
+            ));
+            project.config.port = Some(port);
+        }
+    }
+
+    async fn ensure_container_running(&self, project: &Project) -> Result<String> {
+        if let Some(instance) = &self.ctx.instance {
+            self.ctx.ensure_same_daemon(instance)?;
+        }
+
         let container_id = match &self.ctx.instance {
             Some(instance) => match self.ensure_container_exists(instance).await? {
                 Some(id) => id,
@@ -54,26 +346,35 @@ impl<'a> Reconciler<'a> {
 
         let container_version = self
             .ctx
-            .docker
+            .runtime
             .get_container_postgres_version(&container_id)
             .await?;
 
         self.ensure_matches_project_version(project, &container_id, container_version)
             .await?;
 
+        self.ensure_matches_project_storage(project)?;
+
+        let container_id = self
+            .ensure_matches_project_container(project, container_id)
+            .await?;
+
         if self
             .ctx
-            .docker
+            .runtime
             .is_container_running_by_id(&container_id)
             .await?
         {
             info!("Container is already running");
-            return Ok(());
+            return Ok(container_id);
         }
 
-        use indicatif::{ProgressBar, ProgressStyle};
+        use indicatif::{ProgressBar, ProgressDrawTarget, ProgressStyle};
 
         let spinner = ProgressBar::new_spinner();
+        if self.ctx.json() {
+            spinner.set_draw_target(ProgressDrawTarget::hidden());
+        }
         spinner.enable_steady_tick(Duration::from_millis(100));
         spinner.set_style(
             ProgressStyle::default_spinner()
@@ -96,9 +397,22 @@ impl<'a> Reconciler<'a> {
                         "{}",
                         "Container started successfully".green().bold()
                     ));
-                    return Ok(());
+                    return Ok(container_id);
                 }
                 Err(err) => {
+                    // retrying won't free the port
+                    if let Some(port) = project.config.port
+                        && ports::is_bind_conflict(&err)
+                    {
+                        spinner.finish_with_message(format!("{}", "Port is taken".red()));
+                        return Err(Error::PortTaken {
+                            container: project.container_name(),
+                            port,
+                            cause: err,
+                        }
+                        .into());
+                    }
+
                     spinner.set_message(format!(
                         "{} {}/{} failed: {}",
                         "Attempt".yellow(),
@@ -115,7 +429,36 @@ impl<'a> Reconciler<'a> {
         }
 
         spinner.finish_with_message(format!("{}", "Failed to start container".red()));
-        miette::bail!("Failed to start container after {} attempts", MAX_RETRIES)
+        Err(self.crash_loop_error(project, &container_id).await.into())
+    }
+
+    /// Gathers the exit status and log tail of a container that keeps crashing
+    async fn crash_loop_error(&self, project: &Project, container_id: &str) -> Error {
+        let info = match self.ctx.runtime.inspect_container(container_id).await {
+            Ok(info) => info.unwrap_or_default(),
+            Err(err) => {
+                info!("Failed to inspect crashed container: {err}");
+                Default::default()
+            }
+        };
+
+        let mut lines = Vec::new();
+        let mut logs =
+            self.ctx
+                .runtime
+                .stream_logs(container_id, false, Some(crash::LOG_TAIL_LINES));
+        while let Some(chunk) = logs.next().await {
+            match chunk {
+                Ok(chunk) => lines.extend(chunk.lines().map(str::to_string)),
+                Err(err) => {
+                    info!("Failed to read logs of crashed container: {err}");
+                    break;
+                }
+            }
+        }
+        let tail = lines.split_off(lines.len().saturating_sub(crash::LOG_TAIL_LINES));
+
+        Error::crash_loop(project.container_name(), MAX_RETRIES, &info, &tail)
     }
 
     async fn try_starting_container(
@@ -123,7 +466,7 @@ impl<'a> Reconciler<'a> {
         container_id: &str,
         spinner: &indicatif::ProgressBar,
     ) -> Result<(), miette::Error> {
-        match self.ctx.docker.start_container_by_id(container_id).await {
+        match self.ctx.runtime.start_container(container_id).await {
             Ok(_) => {
                 spinner.set_message(format!(
                     "{} ({}s)...",
@@ -143,7 +486,7 @@ impl<'a> Reconciler<'a> {
 
                 if self
                     .ctx
-                    .docker
+                    .runtime
                     .is_container_running_by_id(container_id)
                     .await?
                 {
@@ -164,36 +507,313 @@ impl<'a> Reconciler<'a> {
             "Creating container".cyan(),
             project.container_name().yellow()
         );
+        let storage = project.data_storage();
+        let is_new_storage = match &storage {
+            DataStorage::Volume(volume) => self.ctx.runtime.ensure_volume(volume).await?,
+            DataStorage::HostDir(dir) => utils::prepare_data_dir(dir)?,
+        };
+        let listen = self.prepare_listen(project)?;
+        // also for data_dir or socket_dir set after `pgd init`
+        utils::ignore_host_dirs(project)?;
+        let owner = ContainerOwner::new(project);
         let id = self
             .ctx
-            .docker
+            .runtime
             .create_postgres_container(
                 &project.container_name(),
                 &project.config.version,
                 &project.config.password,
-                project.config.port,
+                &listen,
+                Some(&storage),
+                &project.config.limits(),
+                &owner,
             )
             .await?;
         info!("{}", "Container created successfully".green());
+        let instance = InstanceState::new(
+            id.clone(),
+            project.config.version,
+            &listen,
+            project.config.limits(),
+            applied_fingerprint(project, is_new_storage),
+            storage,
+            self.ctx.runtime.daemon_host(),
+        );
         self.ctx.state.upsert(
             project.name.clone(),
-            crate::state::InstanceState::new(
-                id.clone(),
-                project.config.version,
-                project.config.port,
-            ),
+            InstanceState {
+                created_at: owner.created_at,
+                ..instance
+            },
         );
         self.ctx.state.save()?;
         Ok(id)
     }
 
+    /// `POSTGRES_PASSWORD` only applies at initdb, so later edits to pgd.toml
+    /// have to be pushed into the running database explicitly
+    async fn ensure_password_applied(
+        &self,
+        project: &Project,
+        container_id: &str,
+    ) -> Result<(), miette::Error> {
+        let Some(mut instance) = self.ctx.state.get(&project.name) else {
+            return Ok(());
+        };
+
+        let fingerprint = utils::password_fingerprint(&project.config.password);
+        if instance.password_fingerprint.as_deref() == Some(fingerprint.as_str()) {
+            return Ok(());
+        }
+
+        self.ctx.progress("Applying password change...".cyan());
+        self.ctx
+            .runtime
+            .wait_until_ready(container_id, READY_TIMEOUT_SECS)
+            .await?;
+        self.ctx
+            .runtime
+            .set_role_password(container_id, &project.config.password)
+            .await?;
+
+        instance.password_fingerprint = Some(fingerprint);
+        self.ctx.state.upsert(project.name.clone(), instance);
+        self.ctx.state.save()?;
+
+        self.ctx.progress(format!(
+            "{} {}",
+            "✓".green().bold(),
+            "Password updated".green()
+        ));
+
+        Ok(())
+    }
+
+    fn ensure_port_available(&self, project: &Project) -> Result<(), miette::Error> {
+        match project.config.port {
+            Some(port) if !ports::is_port_available_at(project.config.bind_address(), port) => {
+                Err(Error::PortInUse {
+                    container: project.container_name(),
+                    port,
+                }
+                .into())
+            }
+            _ => Ok(()),
+        }
+    }
+
+    /// Socket directory and server certificate the container is about to be created with.
+    /// Certificates are issued anew, for the addresses the instance listens on now
+    fn prepare_listen(&self, project: &Project) -> Result<Listen, miette::Error> {
+        let listen = self.ctx.listen(project)?;
+        if let Some(dir) = &listen.socket_dir {
+            utils::prepare_socket_dir(dir)?;
+        }
+        if listen.tls.is_some() {
+            let hosts = tls::server_hosts(project.config.bind_address());
+            self.ctx.ca()?.issue_server_cert(&project.name, &hosts)?;
+        }
+        Ok(listen)
+    }
+
+    /// Port bindings, mounts and resource limits are fixed at creation, so moving to another
+    /// port, host address, socket directory or certificate, or changing limits, means
+    /// recreating the container on top of the same data
+    async fn ensure_matches_project_container(
+        &self,
+        project: &Project,
+        container_id: String,
+    ) -> Result<String, miette::Error> {
+        let Some(instance) = self.ctx.state.get(&project.name) else {
+            return Ok(container_id);
+        };
+
+        let from = instance.listen();
+        let limits = project.config.limits();
+        let moved = from != self.ctx.listen(project)?;
+        if !moved && instance.limits == limits {
+            return Ok(container_id);
+        }
+
+        if instance.port != project.config.port {
+            self.ensure_port_available(project)?;
+        }
+        let listen = self.prepare_listen(project)?;
+        utils::ignore_host_dirs(project)?;
+
+        let storage = match instance.storage() {
+            Some(storage) => Some(storage),
+            None => self
+                .ctx
+                .runtime
+                .get_container_data_volume(&container_id, &instance.postgres_version)
+                .await?
+                .map(DataStorage::Volume),
+        }
+        .ok_or_else(|| {
+            miette!(
+                "Cannot find the data volume of {}, refusing to recreate it",
+                project.container_name()
+            )
+        })?;
+
+        if moved {
+            self.ctx.progress(format!(
+                "{} {} {} {}",
+                "Moving container from".cyan(),
+                from.to_string().yellow(),
+                "to".cyan(),
+                listen.to_string().yellow()
+            ));
+        } else {
+            self.ctx.progress(format!(
+                "{} {}",
+                "Recreating container with".cyan(),
+                limits.to_string().yellow()
+            ));
+        }
+
+        // the anonymous volume of an older instance is only known through its container,
+        // so it is recorded before the container goes away
+        let mut instance = instance;
+        if instance.storage().is_none() {
+            instance.set_storage(storage.clone());
+            self.ctx
+                .state
+                .upsert(project.name.clone(), instance.clone());
+            self.ctx.state.save()?;
+        }
+
+        let was_running = self
+            .ctx
+            .runtime
+            .is_container_running_by_id(&container_id)
+            .await?;
+        if was_running {
+            self.ctx.runtime.stop_container(&container_id, 10).await?;
+        }
+
+        self.ctx
+            .runtime
+            .remove_container(&container_id, false, false)
+            .await?;
+
+        let owner = ContainerOwner {
+            created_at: instance.created_at,
+            ..ContainerOwner::new(project)
+        };
+        let created = self
+            .ctx
+            .runtime
+            .create_postgres_container(
+                &project.container_name(),
+                &project.config.version,
+                &project.config.password,
+                &listen,
+                Some(&storage),
+                &limits,
+                &owner,
+            )
+            .await;
+        let id = match created {
+            Ok(id) => id,
+            Err(err) => {
+                let restored = self
+                    .restore_container(project, &instance, &storage, &owner, was_running)
+                    .await;
+                return Err(match restored {
+                    Ok(()) => err.wrap_err(format!(
+                        "Failed to recreate {}, restored it with its previous settings",
+                        project.container_name()
+                    )),
+                    Err(restore_err) => err.wrap_err(format!(
+                        "Failed to recreate {}, and to restore it ({restore_err}). Its data stays in {storage}",
+                        project.container_name()
+                    )),
+                });
+            }
+        };
+
+        // role password lives in PGDATA, so its fingerprint carries over
+        let mut instance = InstanceState {
+            container_id: id.clone(),
+            limits,
+            ..instance
+        };
+        instance.set_listen(&listen);
+        instance.set_storage(storage);
+        self.ctx.state.upsert(project.name.clone(), instance);
+        self.ctx.state.save()?;
+
+        Ok(id)
+    }
+
+    /// Put back the container [`Self::ensure_matches_project_container`] removed, with the
+    /// settings recorded for it, after its replacement could not be created
+    async fn restore_container(
+        &self,
+        project: &Project,
+        instance: &InstanceState,
+        storage: &DataStorage,
+        owner: &ContainerOwner,
+        start: bool,
+    ) -> Result<(), miette::Error> {
+        let id = self
+            .ctx
+            .runtime
+            .create_postgres_container(
+                &project.container_name(),
+                &instance.postgres_version,
+                &project.config.password,
+                &instance.listen(),
+                Some(storage),
+                &instance.limits,
+                owner,
+            )
+            .await?;
+
+        self.ctx.state.upsert(
+            project.name.clone(),
+            InstanceState {
+                container_id: id.clone(),
+                ..instance.clone()
+            },
+        );
+        self.ctx.state.save()?;
+
+        if start {
+            self.ctx.runtime.start_container(&id).await?;
+        }
+        Ok(())
+    }
+
+    /// Switching storage would silently start over with an empty database,
+    /// so it is left to the user to destroy the instance first
+    fn ensure_matches_project_storage(&self, project: &Project) -> Result<(), miette::Error> {
+        let Some(recorded) = self.ctx.state.get(&project.name).and_then(|i| i.storage()) else {
+            return Ok(());
+        };
+
+        let configured = project.data_storage();
+        if !recorded.satisfies(&configured) {
+            bail!(
+                help = "Run `pgd instance destroy --keep-data` to switch, old data stays in place",
+                "Instance keeps its data in {}, but pgd.toml asks for {}",
+                recorded,
+                configured
+            );
+        }
+
+        Ok(())
+    }
+
     async fn ensure_container_exists(
         &self,
         instance: &InstanceState,
     ) -> Result<Option<String>, miette::Error> {
         let mut container_id = None;
         let id = &instance.container_id;
-        if self.ctx.docker.container_exists_by_id(id).await? {
+        if self.ctx.runtime.container_exists_by_id(id).await? {
             container_id = Some(id.clone());
         }
         Ok(container_id)
@@ -209,7 +829,11 @@ impl<'a> Reconciler<'a> {
             let needs_upgrade = container_version < project.config.version;
 
             if needs_upgrade {
-                bail!("Upgrades are currently unsupported! :(");
+                return Err(Error::UpgradeUnsupported {
+                    from: container_version,
+                    to: project.config.version,
+                }
+                .into());
                 // println!(
                 //     "Upgrading PostgreSQL from {} to {}...",
                 //     container_version, project.config.version
@@ -230,13 +854,622 @@ impl<'a> Reconciler<'a> {
                 //     state.save()?;
                 // }
             } else {
-                miette::bail!(
-                    "Cannot downgrade PostgreSQL from {} to {}. Downgrades are not supported.",
-                    container_version,
-                    project.config.version
-                );
+                return Err(Error::DowngradeUnsupported {
+                    from: container_version,
+                    to: project.config.version,
+                }
+                .into());
             }
         };
         Ok(())
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use std::{path::PathBuf, sync::Arc};
+
+    use super::*;
+    use crate::{
+        config::PGDConfig,
+        controller::{
+            runtime::{
+                BIND_LABEL, ContainerRuntime, PORT_LABEL, SOCKET_DIR_LABEL, TLS_DIR_LABEL,
+                fake::FakeRuntime,
+            },
+            tls::{LocalCa, SERVER_CERT},
+            utils,
+        },
+        state::StateManager,
+    };
+
+    fn project(version: &str, port: u16, password: &str) -> Project {
+        Project {
+            name: "app".to_string(),
+            path: PathBuf::from("/home/user/app"),
+            config: PGDConfig {
+                password: password.to_string(),
+                ..PGDConfig::for_test(version, port)
+            },
+        }
+    }
+
+    fn context(runtime: &FakeRuntime, project: &Project, state: StateManager) -> Context {
+        Context::with_runtime(
+            Arc::new(runtime.clone()),
+            Some(project.clone()),
+            None,
+            state,
+        )
+    }
+
+    /// State left behind by an earlier, successful run of pgd
+    async fn existing_instance(runtime: &FakeRuntime, project: &Project) -> StateManager {
+        let ctx = context(runtime, project, StateManager::in_memory());
+        Reconciler { ctx: &ctx }.reconcile(project).await.unwrap();
+        ctx.state
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_creates_missing_container() {
+        let runtime = FakeRuntime::new();
+        let project = project("17.7", 5432, "secret");
+        let ctx = context(&runtime, &project, StateManager::in_memory());
+
+        Reconciler { ctx: &ctx }.reconcile(&project).await.unwrap();
+
+        let instance = ctx.state.get("app").unwrap();
+        let container = runtime.container(&instance.container_id).unwrap();
+        assert!(container.running);
+        assert_eq!(container.version, project.config.version);
+        assert_eq!(runtime.images(), vec![project.config.version]);
+        assert_eq!(instance.volume_name.as_deref(), Some("pgd-app-data"));
+        assert_eq!(
+            instance.password_fingerprint,
+            Some(utils::password_fingerprint("secret"))
+        );
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_retries_crashing_container() {
+        let runtime = FakeRuntime::new();
+        runtime.crash_next_starts(3);
+        let project = project("17.7", 5432, "secret");
+        let ctx = context(&runtime, &project, StateManager::in_memory());
+
+        Reconciler { ctx: &ctx }.reconcile(&project).await.unwrap();
+
+        assert_eq!(runtime.start_attempts(), 4);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_gives_up_after_max_retries() {
+        let runtime = FakeRuntime::new();
+        runtime.crash_next_starts(MAX_RETRIES);
+        let project = project("17.7", 5432, "secret");
+        let ctx = context(&runtime, &project, StateManager::in_memory());
+
+        let result = Reconciler { ctx: &ctx }.reconcile(&project).await;
+
+        assert!(result.is_err());
+        assert_eq!(runtime.start_attempts(), MAX_RETRIES);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_crash_loop_explains_cause() {
+        let runtime = FakeRuntime::new();
+        runtime.crash_next_starts(MAX_RETRIES);
+        runtime.set_crash_exit(1, false);
+        runtime.set_crash_log(vec![
+            "LOG:  starting PostgreSQL 17.7".to_string(),
+            "FATAL:  database files are incompatible with server".to_string(),
+        ]);
+        let project = project("17.7", 5432, "secret");
+        let ctx = context(&runtime, &project, StateManager::in_memory());
+
+        let err = Reconciler { ctx: &ctx }
+            .reconcile(&project)
+            .await
+            .unwrap_err();
+
+        assert!(err.to_string().contains("exit code 1"));
+        assert!(err.help().unwrap().to_string().contains("major version"));
+        let cause = crash::CrashCause::IncompatibleDataVersion.summary();
+        assert!(err.labels().unwrap().any(|l| l.label() == Some(cause)));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_refuses_version_upgrade() {
+        let runtime = FakeRuntime::new();
+        let state = existing_instance(&runtime, &project("16.11", 5432, "secret")).await;
+        let project = project("17.7", 5432, "secret");
+        let ctx = context(&runtime, &project, state);
+
+        let err = Reconciler { ctx: &ctx }
+            .reconcile(&project)
+            .await
+            .unwrap_err();
+
+        assert!(
+            err.to_string()
+                .contains("Upgrades are currently unsupported")
+        );
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_refuses_version_downgrade() {
+        let runtime = FakeRuntime::new();
+        let state = existing_instance(&runtime, &project("17.7", 5432, "secret")).await;
+        let project = project("16.11", 5432, "secret");
+        let ctx = context(&runtime, &project, state);
+
+        let err = Reconciler { ctx: &ctx }
+            .reconcile(&project)
+            .await
+            .unwrap_err();
+
+        assert!(err.to_string().contains("Cannot downgrade"));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_recreates_container_on_port_drift() {
+        let runtime = FakeRuntime::new();
+        let state = existing_instance(&runtime, &project("17.7", 5432, "secret")).await;
+        let old = state.get("app").unwrap();
+        let port = ports::random_free_port().unwrap();
+        let project = project("17.7", port, "secret");
+        let ctx = context(&runtime, &project, state);
+
+        Reconciler { ctx: &ctx }.reconcile(&project).await.unwrap();
+
+        let instance = ctx.state.get("app").unwrap();
+        assert_ne!(instance.container_id, old.container_id);
+        assert_eq!(instance.port, Some(port));
+        assert_eq!(instance.volume_name, old.volume_name);
+        assert_eq!(instance.password_fingerprint, old.password_fingerprint);
+
+        assert!(runtime.container(&old.container_id).is_none());
+        let container = runtime.container(&instance.container_id).unwrap();
+        assert!(container.running);
+        assert_eq!(container.port, Some(port));
+        assert_eq!(
+            container.volumes.values().collect::<Vec<_>>(),
+            vec!["pgd-app-data"]
+        );
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_restores_container_when_recreate_fails() {
+        let runtime = FakeRuntime::new();
+        let state = existing_instance(&runtime, &project("17.7", 5432, "secret")).await;
+        let old = state.get("app").unwrap();
+        let project = project("17.7", ports::random_free_port().unwrap(), "secret");
+        let ctx = context(&runtime, &project, state);
+        runtime.fail_next_creates(1);
+
+        let err = Reconciler { ctx: &ctx }
+            .reconcile(&project)
+            .await
+            .unwrap_err();
+
+        assert!(
+            err.to_string()
+                .contains("restored it with its previous settings")
+        );
+        let instance = ctx.state.get("app").unwrap();
+        assert_eq!(instance.port, Some(5432));
+        let container = runtime.container(&instance.container_id).unwrap();
+        assert!(container.running);
+        assert_eq!(container.port, Some(5432));
+        assert_eq!(
+            container.volumes.values().collect::<Vec<_>>(),
+            vec![old.volume_name.as_ref().unwrap()]
+        );
+    }
+
+    /// Instance of a release that left PGDATA in an anonymous volume, and that volume
+    async fn legacy_instance(runtime: &FakeRuntime) -> (StateManager, String) {
+        let old = project("17.7", 5432, "secret");
+        let id = runtime
+            .create_postgres_container(
+                &old.container_name(),
+                &old.config.version,
+                &old.config.password,
+                &old.listen(),
+                None,
+                &Limits::default(),
+                &ContainerOwner::new(&old),
+            )
+            .await
+            .unwrap();
+        let volume = runtime.container(&id).unwrap().volumes.into_values().next();
+        let mut instance = InstanceState::new(
+            id,
+            old.config.version,
+            &old.listen(),
+            Limits::default(),
+            Some(utils::password_fingerprint("secret")),
+            old.data_storage(),
+            runtime.daemon_host(),
+        );
+        instance.volume_name = None;
+        let state = StateManager::in_memory();
+        state.upsert("app".to_string(), instance);
+        (state, volume.unwrap())
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_recreated_legacy_instance_starts_again() {
+        let runtime = FakeRuntime::new();
+        let (state, volume) = legacy_instance(&runtime).await;
+        let project = project("17.7", ports::random_free_port().unwrap(), "secret");
+        let ctx = context(&runtime, &project, state);
+
+        Reconciler { ctx: &ctx }.reconcile(&project).await.unwrap();
+        let recreated = ctx.state.get("app").unwrap();
+        let ctx = context(&runtime, &project, ctx.state);
+        let plan = Reconciler { ctx: &ctx }.plan(&project).await.unwrap();
+        Reconciler { ctx: &ctx }.reconcile(&project).await.unwrap();
+
+        assert!(plan.is_empty());
+        assert_eq!(recreated.volume_name.as_deref(), Some(volume.as_str()));
+        let instance = ctx.state.get("app").unwrap();
+        assert_eq!(instance.container_id, recreated.container_id);
+        assert!(runtime.container(&instance.container_id).unwrap().running);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_keeps_anonymous_volume_when_recreate_fails() {
+        let runtime = FakeRuntime::new();
+        let (state, volume) = legacy_instance(&runtime).await;
+        let project = project("17.7", ports::random_free_port().unwrap(), "secret");
+        let ctx = context(&runtime, &project, state);
+        runtime.fail_next_creates(2);
+
+        let err = Reconciler { ctx: &ctx }
+            .reconcile(&project)
+            .await
+            .unwrap_err();
+
+        assert!(
+            err.to_string()
+                .contains("Its data stays in volume anonymous-")
+        );
+        assert!(runtime.containers().is_empty());
+        assert_eq!(
+            ctx.state.get("app").unwrap().volume_name,
+            Some(volume.clone())
+        );
+        assert!(runtime.volumes().contains(&volume));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_moves_new_project_off_taken_port() {
+        let runtime = FakeRuntime::new();
+        let state = StateManager::in_memory();
+        let start = ports::random_free_port().unwrap();
+        let user = crate::config::UserConfig {
+            port_range_start: Some(start),
+            port_range_end: Some(start.saturating_add(20)),
+            ..Default::default()
+        };
+        let allocator = PortAllocator::new(&state, &user).unwrap();
+        let picked = allocator.allocate("app", &[]).unwrap();
+        runtime.take_port(picked);
+
+        let mut project = project("17.7", picked, "secret");
+        let ctx = context(&runtime, &project, state);
+        let allocator = PortAllocator::new(&ctx.state, &user).unwrap();
+        Reconciler { ctx: &ctx }
+            .reconcile_on_free_port(&mut project, &allocator)
+            .await
+            .unwrap();
+
+        let instance = ctx.state.get("app").unwrap();
+        assert_ne!(project.config.port, Some(picked));
+        assert_eq!(instance.port, project.config.port);
+        assert_eq!(runtime.containers().len(), 1);
+        assert!(runtime.container(&instance.container_id).unwrap().running);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_recreates_container_on_bind_drift() {
+        let runtime = FakeRuntime::new();
+        let state = existing_instance(&runtime, &project("17.7", 5432, "secret")).await;
+        let old = state.get("app").unwrap();
+        let mut project = project("17.7", 5432, "secret");
+        project.config.bind = Some("0.0.0.0".parse().unwrap());
+        let ctx = context(&runtime, &project, state);
+
+        let plan = Reconciler { ctx: &ctx }.plan(&project).await.unwrap();
+        Reconciler { ctx: &ctx }.reconcile(&project).await.unwrap();
+
+        assert_eq!(
+            plan[0],
+            Action::RecreateForBind {
+                name: project.container_name(),
+                from: "127.0.0.1".parse().unwrap(),
+                to: "0.0.0.0".parse().unwrap(),
+            }
+        );
+        let instance = ctx.state.get("app").unwrap();
+        assert_ne!(instance.container_id, old.container_id);
+        assert_eq!(instance.bind_address(), project.config.bind_address());
+        let container = runtime.container(&instance.container_id).unwrap();
+        assert_eq!(container.bind, project.config.bind_address());
+        assert_eq!(container.labels[BIND_LABEL], "0.0.0.0");
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_moves_to_socket_only() {
+        let dir = std::env::temp_dir().join(format!("pgd-socket-{}", std::process::id()));
+        let mut project = project("17.7", 5432, "secret");
+        project.path = dir.clone();
+        let runtime = FakeRuntime::new();
+        let state = existing_instance(&runtime, &project).await;
+        project.config.port = None;
+        project.config.socket_dir = Some(PathBuf::from(".pgd/socket"));
+        let ctx = context(&runtime, &project, state);
+
+        let plan = Reconciler { ctx: &ctx }.plan(&project).await.unwrap();
+        Reconciler { ctx: &ctx }.reconcile(&project).await.unwrap();
+
+        assert_eq!(
+            plan[0],
+            Action::RecreateForPort {
+                name: project.container_name(),
+                from: Some(5432),
+                to: None,
+            }
+        );
+        let socket_dir = dir.join(".pgd/socket");
+        assert!(socket_dir.is_dir());
+        let instance = ctx.state.get("app").unwrap();
+        assert_eq!(instance.port, None);
+        assert_eq!(instance.socket_dir.as_ref(), Some(&socket_dir));
+        let container = runtime.container(&instance.container_id).unwrap();
+        assert_eq!(container.port, None);
+        assert!(!container.labels.contains_key(PORT_LABEL));
+        assert_eq!(
+            container.labels[SOCKET_DIR_LABEL],
+            socket_dir.display().to_string()
+        );
+
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_recreates_container_to_serve_tls() {
+        let dir = std::env::temp_dir().join(format!("pgd-tls-drift-{}", std::process::id()));
+        let runtime = FakeRuntime::new();
+        let state = existing_instance(&runtime, &project("17.7", 5432, "secret")).await;
+        let mut project = project("17.7", 5432, "secret");
+        project.config.tls = true;
+        let ctx = context(&runtime, &project, state).with_ca(LocalCa::at(&dir));
+
+        let plan = Reconciler { ctx: &ctx }.plan(&project).await.unwrap();
+        Reconciler { ctx: &ctx }.reconcile(&project).await.unwrap();
+
+        assert_eq!(
+            plan[0],
+            Action::RecreateForTls {
+                name: project.container_name(),
+                enabled: true,
+            }
+        );
+        let server_dir = LocalCa::at(&dir).server_dir("app");
+        assert!(server_dir.join(SERVER_CERT).is_file());
+        let instance = ctx.state.get("app").unwrap();
+        assert_eq!(instance.tls_dir.as_ref(), Some(&server_dir));
+        let container = runtime.container(&instance.container_id).unwrap();
+        assert_eq!(
+            container.labels[TLS_DIR_LABEL],
+            server_dir.display().to_string()
+        );
+
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_recreates_container_on_limits_drift() {
+        let runtime = FakeRuntime::new();
+        let state = existing_instance(&runtime, &project("17.7", 5432, "secret")).await;
+        let old = state.get("app").unwrap();
+        let mut project = project("17.7", 5432, "secret");
+        project.config.memory = Some("1g".parse().unwrap());
+        project.config.shm_size = Some("256m".parse().unwrap());
+        let ctx = context(&runtime, &project, state);
+
+        let plan = Reconciler { ctx: &ctx }.plan(&project).await.unwrap();
+        Reconciler { ctx: &ctx }.reconcile(&project).await.unwrap();
+
+        assert_eq!(
+            plan[0],
+            Action::RecreateForLimits {
+                name: project.container_name(),
+                from: Limits::default(),
+                to: project.config.limits(),
+            }
+        );
+        let instance = ctx.state.get("app").unwrap();
+        assert_ne!(instance.container_id, old.container_id);
+        assert_eq!(instance.volume_name, old.volume_name);
+        assert_eq!(instance.limits, project.config.limits());
+        let container = runtime.container(&instance.container_id).unwrap();
+        assert_eq!(container.limits, project.config.limits());
+        assert!(container.running);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_refuses_port_drift_to_taken_port() {
+        let runtime = FakeRuntime::new();
+        let state = existing_instance(&runtime, &project("17.7", 5432, "secret")).await;
+        let old = state.get("app").unwrap();
+        let taken = std::net::TcpListener::bind(("127.0.0.1", 0)).unwrap();
+        let port = taken.local_addr().unwrap().port();
+        let project = project("17.7", port, "secret");
+        let ctx = context(&runtime, &project, state);
+
+        let err = Reconciler { ctx: &ctx }
+            .reconcile(&project)
+            .await
+            .unwrap_err();
+
+        assert!(err.to_string().contains("already in use"));
+        assert!(runtime.container(&old.container_id).is_some());
+        assert_eq!(ctx.state.get("app").unwrap().port, Some(5432));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_applies_password_drift() {
+        let runtime = FakeRuntime::new();
+        let state = existing_instance(&runtime, &project("17.7", 5432, "secret")).await;
+        let project = project("17.7", 5432, "rotated");
+        let ctx = context(&runtime, &project, state);
+
+        Reconciler { ctx: &ctx }.reconcile(&project).await.unwrap();
+
+        let execs = runtime.execs();
+        let alter_role = execs.iter().any(|exec| {
+            exec.env
+                .iter()
+                .any(|var| var.contains("PASSWORD 'rotated'"))
+        });
+        assert!(alter_role);
+        assert!(
+            execs
+                .iter()
+                .all(|exec| exec.cmd.iter().all(|arg| !arg.contains("rotated")))
+        );
+        assert_eq!(
+            ctx.state.get("app").unwrap().password_fingerprint,
+            Some(utils::password_fingerprint("rotated"))
+        );
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_failed_password_change_hides_password() {
+        let runtime = FakeRuntime::new();
+        let state = existing_instance(&runtime, &project("17.7", 5432, "secret")).await;
+        let project = project("17.7", 5432, "it's-rotated");
+        let ctx = context(&runtime, &project, state);
+        runtime.fail_execs(
+            "psql",
+            "ERROR:  syntax error\nLINE 1: ALTER ROLE postgres WITH PASSWORD 'it''s-rotated';",
+        );
+
+        let err = Reconciler { ctx: &ctx }
+            .reconcile(&project)
+            .await
+            .unwrap_err();
+
+        let message = err.chain().map(|e| e.to_string()).collect::<Vec<_>>();
+        assert!(message.iter().any(|m| m.contains("syntax error")));
+        assert!(message.iter().all(|m| !m.contains("rotated")));
+        assert_eq!(
+            ctx.state.get("app").unwrap().password_fingerprint,
+            Some(utils::password_fingerprint("secret"))
+        );
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_refuses_instance_of_another_daemon() {
+        let runtime = FakeRuntime::new();
+        let project = project("17.7", 5432, "secret");
+        let state = existing_instance(&runtime, &project).await;
+        runtime.set_daemon_host("tcp://build-vm:2376");
+        let ctx = context(&runtime, &project, state);
+
+        let err = Reconciler { ctx: &ctx }
+            .reconcile(&project)
+            .await
+            .unwrap_err();
+
+        assert!(err.to_string().contains("fake://local"));
+        assert_eq!(runtime.containers().len(), 1);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_plans_fresh_instance_without_side_effects() {
+        let runtime = FakeRuntime::new();
+        let project = project("17.7", 5432, "secret");
+        let ctx = context(&runtime, &project, StateManager::in_memory());
+
+        let actions = Reconciler { ctx: &ctx }.plan(&project).await.unwrap();
+
+        let name = project.container_name();
+        assert_eq!(
+            actions,
+            vec![
+                Action::PullImage {
+                    version: project.config.version
+                },
+                Action::CreateVolume {
+                    name: "pgd-app-data".to_string()
+                },
+                Action::CreateContainer {
+                    name: name.clone(),
+                    version: project.config.version,
+                    port: Some(5432)
+                },
+                Action::Start { name },
+            ]
+        );
+        assert!(runtime.images().is_empty());
+        assert!(runtime.volumes().is_empty());
+        assert!(runtime.containers().is_empty());
+        assert!(ctx.state.get("app").is_none());
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_plans_nothing_for_running_instance() {
+        let runtime = FakeRuntime::new();
+        let project = project("17.7", 5432, "secret");
+        let state = existing_instance(&runtime, &project).await;
+        let ctx = context(&runtime, &project, state);
+
+        let actions = Reconciler { ctx: &ctx }.plan(&project).await.unwrap();
+
+        assert!(actions.is_empty());
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_plans_port_and_password_drift() {
+        let runtime = FakeRuntime::new();
+        let state = existing_instance(&runtime, &project("17.7", 5432, "secret")).await;
+        let old = state.get("app").unwrap();
+        let port = ports::random_free_port().unwrap();
+        let project = project("17.7", port, "rotated");
+        let ctx = context(&runtime, &project, state);
+
+        let actions = Reconciler { ctx: &ctx }.plan(&project).await.unwrap();
+
+        let name = project.container_name();
+        assert_eq!(
+            actions,
+            vec![
+                Action::RecreateForPort {
+                    name: name.clone(),
+                    from: Some(5432),
+                    to: Some(port)
+                },
+                Action::Start { name: name.clone() },
+                Action::ApplyPassword { name },
+            ]
+        );
+        assert!(runtime.container(&old.container_id).is_some());
+        assert!(runtime.execs().is_empty());
+    }
+
+    #[test]
+    fn test_action_json() {
+        let action = Action::PullImage {
+            version: "17.7".parse().unwrap(),
+        };
+
+        assert_eq!(
+            serde_json::to_string(&action).unwrap(),
+            r#"{"action":"pull_image","version":"17.7"}"#
+        );
+    }
+}
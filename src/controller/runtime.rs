@@ -0,0 +1,330 @@
+use std::{collections::HashMap, path::PathBuf, str::FromStr, sync::Arc};
+
+use async_trait::async_trait;
+use futures::stream::BoxStream;
+use miette::{Context, Result, miette};
+
+use crate::{
+    config::{DataStorage, Limits, Listen, PostgresVersion, Project},
+    consts::{DATABASE, USERNAME},
+    error::Error,
+};
+
+#[cfg(any(test, feature = "testing"))]
+pub mod fake;
+
+pub const VERSION_LABEL: &str = "pgd.postgres.version";
+pub const PROJECT_LABEL: &str = "pgd.project";
+pub const PROJECT_PATH_LABEL: &str = "pgd.project.path";
+pub const PORT_LABEL: &str = "pgd.port";
+pub const CREATED_AT_LABEL: &str = "pgd.created_at";
+/// Host directory holding PGDATA, bind mounts are not listed among volumes
+pub const DATA_DIR_LABEL: &str = "pgd.data_dir";
+/// Host address the port is published on
+pub const BIND_LABEL: &str = "pgd.bind";
+/// Host directory the socket directory is mounted to
+pub const SOCKET_DIR_LABEL: &str = "pgd.socket_dir";
+/// Host directory of the server certificate and key
+pub const TLS_DIR_LABEL: &str = "pgd.tls_dir";
+
+/// Socket directory of the postgres image
+pub const SOCKET_MOUNT_PATH: &str = "/var/run/postgresql";
+/// Where the server certificate and key show up in the container
+pub const TLS_MOUNT_PATH: &str = "/etc/pgd/tls";
+
+/// Project a container belongs to, stamped on it so state can be rebuilt from the daemon
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ContainerOwner {
+    pub project: String,
+    /// Canonical path of the project directory
+    pub path: PathBuf,
+    /// Unix timestamp, carried over when the container is recreated
+    pub created_at: u64,
+}
+
+impl ContainerOwner {
+    /// Owner of a container created just now
+    pub fn new(project: &Project) -> Self {
+        Self {
+            project: project.name.clone(),
+            path: project.path.clone(),
+            created_at: std::time::SystemTime::now()
+                .duration_since(std::time::UNIX_EPOCH)
+                .unwrap()
+                .as_secs(),
+        }
+    }
+}
+
+/// Labels of a project container, read back by `pgd doctor --repair`
+pub fn instance_labels(
+    version: &PostgresVersion,
+    listen: &Listen,
+    storage: Option<&DataStorage>,
+    owner: &ContainerOwner,
+) -> HashMap<String, String> {
+    let mut labels = HashMap::from([
+        (VERSION_LABEL.to_string(), version.to_string()),
+        (PROJECT_LABEL.to_string(), owner.project.clone()),
+        (
+            PROJECT_PATH_LABEL.to_string(),
+            owner.path.display().to_string(),
+        ),
+        (CREATED_AT_LABEL.to_string(), owner.created_at.to_string()),
+    ]);
+    if let Some(address) = listen.tcp {
+        labels.insert(PORT_LABEL.to_string(), address.port().to_string());
+        labels.insert(BIND_LABEL.to_string(), address.ip().to_string());
+    }
+    if let Some(dir) = &listen.socket_dir {
+        labels.insert(SOCKET_DIR_LABEL.to_string(), dir.display().to_string());
+    }
+    if let Some(dir) = &listen.tls {
+        labels.insert(TLS_DIR_LABEL.to_string(), dir.display().to_string());
+    }
+    if let Some(DataStorage::HostDir(dir)) = storage {
+        labels.insert(DATA_DIR_LABEL.to_string(), dir.display().to_string());
+    }
+    labels
+}
+
+/// Volume declared by the postgres image; moved up one level in 18 to allow `pg_upgrade --link`
+pub fn data_mount_path(ver: &PostgresVersion) -> &'static str {
+    if ver.major >= 18 {
+        "/var/lib/postgresql"
+    } else {
+        "/var/lib/postgresql/data"
+    }
+}
+
+/// What pgd needs to know about an existing container
+#[derive(Debug, Clone, Default)]
+pub struct ContainerInfo {
+    pub running: bool,
+    pub labels: HashMap<String, String>,
+    /// Named volumes by mount destination
+    pub volumes: HashMap<String, String>,
+    /// Exit code of the last run, if it has exited
+    pub exit_code: Option<i64>,
+    pub oom_killed: bool,
+}
+
+/// What a command run inside a container left behind
+#[derive(Debug, Clone, Default)]
+pub struct ExecOutput {
+    /// `None` if the runtime could not tell
+    pub exit_code: Option<i64>,
+    /// Stdout and stderr, interleaved
+    pub output: String,
+}
+
+impl ExecOutput {
+    pub fn success(&self) -> bool {
+        self.exit_code.is_none_or(|code| code == 0)
+    }
+}
+
+/// What the daemon reports about itself, for `pgd doctor`
+#[derive(Debug, Clone, Default)]
+pub struct DaemonInfo {
+    pub version: String,
+    pub api_version: String,
+    /// RFC 3339
+    pub system_time: Option<String>,
+    /// Where images and volumes are stored, on the daemon's machine
+    pub root_dir: Option<PathBuf>,
+}
+
+/// Everything pgd asks of a container engine. Implemented by [`DockerController`]
+/// and by the in-memory `FakeRuntime` of the `testing` feature.
+///
+/// [`DockerController`]: crate::controller::docker::DockerController
+#[async_trait]
+pub trait ContainerRuntime: Send + Sync {
+    /// Fresh connection to the same engine, for cleanup outside of the caller's async runtime
+    async fn reconnect(&self) -> Result<Arc<dyn ContainerRuntime>>;
+
+    /// Address of the daemon, recorded with each instance to tell apart containers of other daemons
+    fn daemon_host(&self) -> String;
+
+    async fn available_versions(&self) -> Result<Vec<PostgresVersion>>;
+
+    async fn daemon_info(&self) -> Result<DaemonInfo>;
+
+    /// Pulled postgres images with their size in bytes
+    async fn postgres_images(&self) -> Result<Vec<(PostgresVersion, u64)>>;
+
+    /// Whether the image of this version is already pulled
+    async fn has_image(&self, ver: &PostgresVersion) -> Result<bool>;
+
+    async fn ensure_version_downloaded(&self, ver: &PostgresVersion) -> Result<()>;
+
+    #[allow(clippy::too_many_arguments)]
+    async fn create_postgres_container(
+        &self,
+        container_name: &str,
+        version: &PostgresVersion,
+        password: &str,
+        listen: &Listen,
+        storage: Option<&DataStorage>,
+        limits: &Limits,
+        owner: &ContainerOwner,
+    ) -> Result<String>;
+
+    /// Throwaway container with PGDATA on tmpfs and durability turned off for speed
+    async fn create_ephemeral_container(
+        &self,
+        container_name: &str,
+        version: &PostgresVersion,
+        password: &str,
+        port: u16,
+    ) -> Result<String>;
+
+    async fn start_container(&self, container_id: &str) -> Result<()>;
+
+    async fn stop_container(&self, container_id: &str, timeout: i32) -> Result<()>;
+
+    async fn remove_container(
+        &self,
+        container_id: &str,
+        force: bool,
+        remove_volumes: bool,
+    ) -> Result<()>;
+
+    /// Containers stamped with the `pgd.project` label, running or not, by id
+    async fn list_project_containers(&self) -> Result<Vec<(String, ContainerInfo)>>;
+
+    /// `None` if there is no such container
+    async fn inspect_container(&self, container_id: &str) -> Result<Option<ContainerInfo>>;
+
+    /// Run a command inside the container. Secrets go in `env` (`KEY=value`), which unlike
+    /// arguments shows up neither in `docker inspect` nor in `ps`
+    async fn exec_in_container(
+        &self,
+        container_id: &str,
+        cmd: Vec<&str>,
+        env: Vec<&str>,
+    ) -> Result<ExecOutput>;
+
+    /// Only the last `tail` lines of the log so far when set, all of them otherwise
+    fn stream_logs(
+        &self,
+        container_id: &str,
+        follow: bool,
+        tail: Option<usize>,
+    ) -> BoxStream<'static, Result<String>>;
+
+    async fn volume_exists(&self, volume_name: &str) -> Result<bool>;
+
+    /// Create a named volume for PGDATA. Returns `false` if the volume already existed
+    async fn ensure_volume(&self, volume_name: &str) -> Result<bool>;
+
+    async fn remove_volume(&self, volume_name: &str) -> Result<()>;
+
+    async fn container_exists_by_id(&self, container_id: &str) -> Result<bool> {
+        Ok(self.inspect_container(container_id).await?.is_some())
+    }
+
+    async fn is_container_running_by_id(&self, container_id: &str) -> Result<bool> {
+        let container = self
+            .inspect_container(container_id)
+            .await?
+            .ok_or_else(|| miette!("Container {} not found", container_id))?;
+
+        Ok(container.running)
+    }
+
+    async fn get_container_postgres_version(&self, container_id: &str) -> Result<PostgresVersion> {
+        let container = self
+            .inspect_container(container_id)
+            .await?
+            .ok_or_else(|| miette!("Container {} not found", container_id))?;
+
+        let version_str =
+            container
+                .labels
+                .get(VERSION_LABEL)
+                .ok_or_else(|| Error::VersionLabelMissing {
+                    container: container_id.to_string(),
+                    label: VERSION_LABEL,
+                })?;
+
+        PostgresVersion::from_str(version_str)
+            .map_err(|_| miette!("Invalid version in label: {}", version_str))
+    }
+
+    /// Name of the volume holding PGDATA of a container, if it has one
+    async fn get_container_data_volume(
+        &self,
+        container_id: &str,
+        version: &PostgresVersion,
+    ) -> Result<Option<String>> {
+        let container = self
+            .inspect_container(container_id)
+            .await?
+            .ok_or_else(|| miette!("Container {} not found", container_id))?;
+
+        Ok(container.volumes.get(data_mount_path(version)).cloned())
+    }
+
+    /// Wait until postgres inside the container accepts connections
+    async fn wait_until_ready(&self, container_id: &str, timeout_secs: u64) -> Result<()> {
+        for _ in 0..timeout_secs {
+            let ready = self
+                // over TCP, so the temporary server of the init scripts doesn't count
+                .exec_in_container(
+                    container_id,
+                    vec!["pg_isready", "-h", "127.0.0.1", "-U", USERNAME],
+                    vec![],
+                )
+                .await
+                .is_ok_and(|exec| exec.success());
+            if ready {
+                return Ok(());
+            }
+            tokio::time::sleep(tokio::time::Duration::from_secs(1)).await;
+        }
+
+        miette::bail!(
+            "Postgres did not become ready within {} seconds",
+            timeout_secs
+        )
+    }
+
+    /// Change the password of the superuser role inside a running container.
+    /// The statement reaches psql on stdin through the environment, and a failing one
+    /// is kept out of the server log, so the password never shows up outside the database
+    async fn set_role_password(&self, container_id: &str, password: &str) -> Result<()> {
+        let escaped = password.replace('\'', "''");
+        let statement = format!(
+            "SET log_min_error_statement = panic; ALTER ROLE {USERNAME} WITH PASSWORD '{escaped}';"
+        );
+        let env = format!("PGD_STATEMENT={statement}");
+        let script = format!(
+            "printf '%s\\n' \"$PGD_STATEMENT\" | psql -U {USERNAME} -d {DATABASE} -v ON_ERROR_STOP=1 -q"
+        );
+
+        let exec = self
+            .exec_in_container(container_id, vec!["sh", "-c", &script], vec![&env])
+            .await
+            .wrap_err("Failed to update role password")?;
+
+        if !exec.success() {
+            // psql quotes the failing statement back
+            let mut output = exec.output.trim().to_string();
+            for secret in [escaped.as_str(), password] {
+                if !secret.is_empty() {
+                    output = output.replace(secret, "********");
+                }
+            }
+            return Err(miette!(
+                "psql exited with code {}: {}",
+                exec.exit_code.unwrap_or_default(),
+                output
+            )
+            .wrap_err("Failed to update role password"));
+        }
+
+        Ok(())
+    }
+}
@@ -0,0 +1,510 @@
+//! In-memory [`ContainerRuntime`], so reconcile logic can be tested without a daemon
+
+use std::{
+    collections::{BTreeSet, HashMap},
+    net::{IpAddr, SocketAddr},
+    str::FromStr,
+    sync::Arc,
+};
+
+use async_trait::async_trait;
+use futures::{StreamExt, stream::BoxStream};
+use miette::{Result, miette};
+use parking_lot::Mutex;
+
+use crate::{
+    config::{DataStorage, LOOPBACK, Limits, Listen, PostgresVersion},
+    controller::runtime::{
+        ContainerInfo, ContainerOwner, ContainerRuntime, DaemonInfo, ExecOutput, PROJECT_LABEL,
+        VERSION_LABEL, data_mount_path, instance_labels,
+    },
+};
+
+#[derive(Debug, Clone)]
+pub struct FakeContainer {
+    pub name: String,
+    pub version: PostgresVersion,
+    pub port: Option<u16>,
+    pub bind: IpAddr,
+    pub socket_dir: Option<String>,
+    pub password: String,
+    pub ephemeral: bool,
+    pub running: bool,
+    pub exit_code: Option<i64>,
+    pub oom_killed: bool,
+    pub labels: HashMap<String, String>,
+    /// Volume names by mount destination, anonymous ones included
+    pub volumes: HashMap<String, String>,
+    pub bind_mounts: HashMap<String, String>,
+    pub limits: Limits,
+}
+
+/// Command passed to `exec_in_container`, with its environment
+#[derive(Debug, Clone)]
+pub struct FakeExec {
+    pub cmd: Vec<String>,
+    pub env: Vec<String>,
+}
+
+impl FakeContainer {
+    fn info(&self) -> ContainerInfo {
+        ContainerInfo {
+            running: self.running,
+            labels: self.labels.clone(),
+            volumes: self.volumes.clone(),
+            exit_code: self.exit_code,
+            oom_killed: self.oom_killed,
+        }
+    }
+}
+
+#[derive(Default)]
+struct Inner {
+    containers: HashMap<String, FakeContainer>,
+    volumes: BTreeSet<String>,
+    images: BTreeSet<PostgresVersion>,
+    execs: Vec<FakeExec>,
+    logs: HashMap<String, Vec<String>>,
+    start_attempts: usize,
+    crashing_starts: usize,
+    crash_exit: Option<(i64, bool)>,
+    crash_log: Vec<String>,
+    next_id: usize,
+    daemon_host: Option<String>,
+    taken_ports: BTreeSet<u16>,
+    failing_creates: usize,
+    failing_execs: Vec<(String, String)>,
+    failing_list: bool,
+}
+
+impl Inner {
+    /// Like docker, containers are found by id or by name
+    fn resolve(&self, container_id: &str) -> Option<String> {
+        if self.containers.contains_key(container_id) {
+            return Some(container_id.to_string());
+        }
+        self.containers
+            .iter()
+            .find(|(_, c)| c.name == container_id)
+            .map(|(id, _)| id.clone())
+    }
+
+    fn container_mut(&mut self, container_id: &str) -> Result<&mut FakeContainer> {
+        self.resolve(container_id)
+            .and_then(|id| self.containers.get_mut(&id))
+            .ok_or_else(|| miette!("No such container: {}", container_id))
+    }
+
+    fn next_id(&mut self) -> String {
+        self.next_id += 1;
+        format!("{:064x}", self.next_id)
+    }
+}
+
+/// Clones share the same containers, volumes and images
+#[derive(Clone, Default)]
+pub struct FakeRuntime(Arc<Mutex<Inner>>);
+
+impl FakeRuntime {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// The next `n` starts succeed, but the container exits right away like a crash-looping one
+    pub fn crash_next_starts(&self, n: usize) {
+        self.0.lock().crashing_starts = n;
+    }
+
+    /// How crashing starts exit, 1 and not OOM-killed unless set
+    pub fn set_crash_exit(&self, exit_code: i64, oom_killed: bool) {
+        self.0.lock().crash_exit = Some((exit_code, oom_killed));
+    }
+
+    /// Lines every crashing start appends to the log of its container
+    pub fn set_crash_log(&self, lines: Vec<String>) {
+        self.0.lock().crash_log = lines;
+    }
+
+    /// Lines returned by `stream_logs` for a container
+    pub fn set_logs(&self, container_id: &str, lines: Vec<String>) {
+        self.0.lock().logs.insert(container_id.to_string(), lines);
+    }
+
+    /// Starting a container bound to `port` fails, as if another program listened on it
+    pub fn take_port(&self, port: u16) {
+        self.0.lock().taken_ports.insert(port);
+    }
+
+    /// The next `n` project containers fail to be created, like on a failed pull or a bad mount
+    pub fn fail_next_creates(&self, n: usize) {
+        self.0.lock().failing_creates = n;
+    }
+
+    /// Execs with an argument containing `needle` exit with 1 and print `output`
+    pub fn fail_execs(&self, needle: &str, output: &str) {
+        self.0
+            .lock()
+            .failing_execs
+            .push((needle.to_string(), output.to_string()));
+    }
+
+    /// Listing project containers fails, like on a daemon that times out
+    pub fn fail_listing(&self) {
+        self.0.lock().failing_list = true;
+    }
+
+    /// Pretend to be another daemon, like after switching docker contexts
+    pub fn set_daemon_host(&self, host: &str) {
+        self.0.lock().daemon_host = Some(host.to_string());
+    }
+
+    pub fn container(&self, container_id: &str) -> Option<FakeContainer> {
+        self.0.lock().containers.get(container_id).cloned()
+    }
+
+    pub fn containers(&self) -> HashMap<String, FakeContainer> {
+        self.0.lock().containers.clone()
+    }
+
+    pub fn volumes(&self) -> Vec<String> {
+        self.0.lock().volumes.iter().cloned().collect()
+    }
+
+    pub fn images(&self) -> Vec<PostgresVersion> {
+        self.0.lock().images.iter().copied().collect()
+    }
+
+    /// Every command passed to `exec_in_container`, in order
+    pub fn execs(&self) -> Vec<FakeExec> {
+        self.0.lock().execs.clone()
+    }
+
+    pub fn start_attempts(&self) -> usize {
+        self.0.lock().start_attempts
+    }
+
+    fn create(
+        &self,
+        container_name: &str,
+        version: &PostgresVersion,
+        password: &str,
+        listen: &Listen,
+        storage: Option<&DataStorage>,
+        owner: Option<&ContainerOwner>,
+    ) -> Result<String> {
+        let ephemeral = owner.is_none();
+        let mut inner = self.0.lock();
+
+        if inner.containers.values().any(|c| c.name == container_name) {
+            miette::bail!("Container name {} is already in use", container_name);
+        }
+
+        let id = inner.next_id();
+        let target = data_mount_path(version).to_string();
+
+        let mut volumes = HashMap::new();
+        let mut bind_mounts = HashMap::new();
+        match storage {
+            Some(DataStorage::Volume(volume)) => {
+                volumes.insert(target, volume.clone());
+            }
+            Some(DataStorage::HostDir(dir)) => {
+                bind_mounts.insert(target, dir.display().to_string());
+            }
+            None if ephemeral => {}
+            None => {
+                let volume = format!("anonymous-{}", &id[56..]);
+                inner.volumes.insert(volume.clone());
+                volumes.insert(target, volume);
+            }
+        }
+
+        let labels = match owner {
+            Some(owner) => instance_labels(version, listen, storage, owner),
+            None => HashMap::from([
+                (VERSION_LABEL.to_string(), version.to_string()),
+                ("pgd.ephemeral".to_string(), "true".to_string()),
+            ]),
+        };
+
+        inner.containers.insert(
+            id.clone(),
+            FakeContainer {
+                name: container_name.to_string(),
+                version: *version,
+                port: listen.tcp.map(|address| address.port()),
+                bind: listen.tcp.map_or(LOOPBACK, |address| address.ip()),
+                socket_dir: listen
+                    .socket_dir
+                    .as_ref()
+                    .map(|dir| dir.display().to_string()),
+                password: password.to_string(),
+                ephemeral,
+                running: false,
+                exit_code: None,
+                oom_killed: false,
+                labels,
+                volumes,
+                bind_mounts,
+                limits: Limits::default(),
+            },
+        );
+
+        Ok(id)
+    }
+}
+
+#[async_trait]
+impl ContainerRuntime for FakeRuntime {
+    async fn reconnect(&self) -> Result<Arc<dyn ContainerRuntime>> {
+        Ok(Arc::new(self.clone()))
+    }
+
+    fn daemon_host(&self) -> String {
+        self.0
+            .lock()
+            .daemon_host
+            .clone()
+            .unwrap_or_else(|| "fake://local".to_string())
+    }
+
+    async fn available_versions(&self) -> Result<Vec<PostgresVersion>> {
+        Ok(vec!["18.1", "17.7", "16.11", "15.15", "14.20"]
+            .into_iter()
+            .map(|v| PostgresVersion::from_str(v).unwrap())
+            .collect())
+    }
+
+    async fn daemon_info(&self) -> Result<DaemonInfo> {
+        Ok(DaemonInfo {
+            version: "fake".to_string(),
+            api_version: "1.49".to_string(),
+            system_time: Some(chrono::Utc::now().to_rfc3339()),
+            root_dir: None,
+        })
+    }
+
+    async fn postgres_images(&self) -> Result<Vec<(PostgresVersion, u64)>> {
+        Ok(self.0.lock().images.iter().map(|v| (*v, 0)).collect())
+    }
+
+    async fn has_image(&self, ver: &PostgresVersion) -> Result<bool> {
+        Ok(self.0.lock().images.contains(ver))
+    }
+
+    async fn ensure_version_downloaded(&self, ver: &PostgresVersion) -> Result<()> {
+        self.0.lock().images.insert(*ver);
+        Ok(())
+    }
+
+    async fn create_postgres_container(
+        &self,
+        container_name: &str,
+        version: &PostgresVersion,
+        password: &str,
+        listen: &Listen,
+        storage: Option<&DataStorage>,
+        limits: &Limits,
+        owner: &ContainerOwner,
+    ) -> Result<String> {
+        {
+            let mut inner = self.0.lock();
+            if inner.failing_creates > 0 {
+                inner.failing_creates -= 1;
+                miette::bail!("invalid mount config for type \"bind\"");
+            }
+        }
+
+        let id = self.create(
+            container_name,
+            version,
+            password,
+            listen,
+            storage,
+            Some(owner),
+        )?;
+        self.0.lock().container_mut(&id)?.limits = *limits;
+        Ok(id)
+    }
+
+    async fn create_ephemeral_container(
+        &self,
+        container_name: &str,
+        version: &PostgresVersion,
+        password: &str,
+        port: u16,
+    ) -> Result<String> {
+        let listen = Listen {
+            tcp: Some(SocketAddr::new(LOOPBACK, port)),
+            socket_dir: None,
+            tls: None,
+        };
+        self.create(container_name, version, password, &listen, None, None)
+    }
+
+    async fn start_container(&self, container_id: &str) -> Result<()> {
+        let mut inner = self.0.lock();
+        inner.start_attempts += 1;
+
+        let taken_ports = inner.taken_ports.clone();
+        let container = inner.container_mut(container_id)?;
+        if let Some(port) = container.port
+            && taken_ports.contains(&port)
+        {
+            return Err(miette!(
+                "driver failed programming external connectivity: Bind for {}: port is already allocated",
+                SocketAddr::new(container.bind, port)
+            ));
+        }
+
+        let crashes = inner.crashing_starts > 0;
+        if crashes {
+            inner.crashing_starts -= 1;
+        }
+
+        let (exit_code, oom_killed) = inner.crash_exit.unwrap_or((1, false));
+        if crashes {
+            let log = inner.crash_log.clone();
+            inner
+                .logs
+                .entry(container_id.to_string())
+                .or_default()
+                .extend(log);
+        }
+        let container = inner.container_mut(container_id)?;
+        container.running = !crashes;
+        if crashes {
+            container.exit_code = Some(exit_code);
+            container.oom_killed = oom_killed;
+        }
+        Ok(())
+    }
+
+    async fn stop_container(&self, container_id: &str, _timeout: i32) -> Result<()> {
+        self.0.lock().container_mut(container_id)?.running = false;
+        Ok(())
+    }
+
+    async fn remove_container(
+        &self,
+        container_id: &str,
+        force: bool,
+        remove_volumes: bool,
+    ) -> Result<()> {
+        let mut inner = self.0.lock();
+
+        let container = inner.container_mut(container_id)?;
+        if container.running && !force {
+            miette::bail!("Cannot remove running container {}", container_id);
+        }
+
+        let id = inner.resolve(container_id).unwrap();
+        let container = inner.containers.remove(&id).unwrap();
+        if remove_volumes {
+            // like docker, only anonymous volumes go along with the container
+            for volume in container.volumes.values() {
+                if volume.starts_with("anonymous-") {
+                    inner.volumes.remove(volume);
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    async fn list_project_containers(&self) -> Result<Vec<(String, ContainerInfo)>> {
+        let inner = self.0.lock();
+        if inner.failing_list {
+            miette::bail!("request timed out");
+        }
+        Ok(inner
+            .containers
+            .iter()
+            .filter(|(_, c)| c.labels.contains_key(PROJECT_LABEL))
+            .map(|(id, c)| (id.clone(), c.info()))
+            .collect())
+    }
+
+    async fn inspect_container(&self, container_id: &str) -> Result<Option<ContainerInfo>> {
+        let inner = self.0.lock();
+        Ok(inner
+            .resolve(container_id)
+            .and_then(|id| inner.containers.get(&id))
+            .map(FakeContainer::info))
+    }
+
+    async fn exec_in_container(
+        &self,
+        container_id: &str,
+        cmd: Vec<&str>,
+        env: Vec<&str>,
+    ) -> Result<ExecOutput> {
+        let mut inner = self.0.lock();
+
+        if !inner.container_mut(container_id)?.running {
+            miette::bail!("Container {} is not running", container_id);
+        }
+
+        let cmd: Vec<String> = cmd.iter().map(|s| s.to_string()).collect();
+        let failure = inner
+            .failing_execs
+            .iter()
+            .find(|(needle, _)| cmd.iter().any(|arg| arg.contains(needle.as_str())))
+            .map(|(_, output)| output.clone());
+        inner.execs.push(FakeExec {
+            cmd,
+            env: env.iter().map(|s| s.to_string()).collect(),
+        });
+
+        Ok(match failure {
+            Some(output) => ExecOutput {
+                exit_code: Some(1),
+                output,
+            },
+            None => ExecOutput::default(),
+        })
+    }
+
+    fn stream_logs(
+        &self,
+        container_id: &str,
+        _follow: bool,
+        tail: Option<usize>,
+    ) -> BoxStream<'static, Result<String>> {
+        let mut lines = self
+            .0
+            .lock()
+            .logs
+            .get(container_id)
+            .cloned()
+            .unwrap_or_default();
+        if let Some(tail) = tail {
+            lines = lines.split_off(lines.len().saturating_sub(tail));
+        }
+
+        futures::stream::iter(lines.into_iter().map(Ok)).boxed()
+    }
+
+    async fn volume_exists(&self, volume_name: &str) -> Result<bool> {
+        Ok(self.0.lock().volumes.contains(volume_name))
+    }
+
+    async fn ensure_volume(&self, volume_name: &str) -> Result<bool> {
+        Ok(self.0.lock().volumes.insert(volume_name.to_string()))
+    }
+
+    async fn remove_volume(&self, volume_name: &str) -> Result<()> {
+        let mut inner = self.0.lock();
+
+        let in_use = inner
+            .containers
+            .values()
+            .any(|c| c.volumes.values().any(|v| v == volume_name));
+        if in_use {
+            miette::bail!("Volume {} is in use", volume_name);
+        }
+
+        inner.volumes.remove(volume_name);
+        Ok(())
+    }
+}
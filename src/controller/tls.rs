@@ -0,0 +1,226 @@
+//! Local certificate authority, so instances can serve TLS like production does
+
+use std::{
+    net::{IpAddr, Ipv4Addr, Ipv6Addr},
+    path::{Path, PathBuf},
+};
+
+use miette::{Context, IntoDiagnostic, Result, miette};
+use rcgen::{
+    BasicConstraints, CertificateParams, DnType, ExtendedKeyUsagePurpose, IsCa, Issuer, KeyPair,
+    KeyUsagePurpose,
+};
+
+use crate::{controller::ports, paths};
+
+const CA_CERT: &str = "ca.crt";
+const CA_KEY: &str = "ca.key";
+pub const SERVER_CERT: &str = "server.crt";
+pub const SERVER_KEY: &str = "server.key";
+const CA_NAME: &str = "pgd local CA";
+
+/// Per-user CA that signs the server certificates of all instances
+#[derive(Debug, Clone)]
+pub struct LocalCa {
+    dir: PathBuf,
+}
+
+impl LocalCa {
+    /// CA under the state directory
+    pub fn open() -> Result<Self> {
+        Ok(Self::at(paths::state_dir()?.join("tls")))
+    }
+
+    pub fn at(dir: impl Into<PathBuf>) -> Self {
+        Self { dir: dir.into() }
+    }
+
+    /// What clients pass as `sslrootcert` to verify instances
+    pub fn root_cert(&self) -> PathBuf {
+        self.dir.join(CA_CERT)
+    }
+
+    /// Directory holding the server certificate and key of a project
+    pub fn server_dir(&self, project: &str) -> PathBuf {
+        self.dir.join("servers").join(project)
+    }
+
+    /// Sign a fresh server certificate for the project, creating the CA on first use
+    pub fn issue_server_cert(&self, project: &str, hosts: &[String]) -> Result<PathBuf> {
+        let issuer = self.issuer()?;
+
+        let mut params = CertificateParams::new(hosts.to_vec())
+            .into_diagnostic()
+            .wrap_err("Invalid host name for the server certificate")?;
+        params
+            .distinguished_name
+            .push(DnType::CommonName, format!("pgd {project}"));
+        params.use_authority_key_identifier_extension = true;
+        params.key_usages = vec![KeyUsagePurpose::DigitalSignature];
+        params.extended_key_usages = vec![ExtendedKeyUsagePurpose::ServerAuth];
+
+        let key = KeyPair::generate().into_diagnostic()?;
+        let cert = params.signed_by(&key, &issuer).into_diagnostic()?;
+
+        let dir = self.server_dir(project);
+        create_dir(&dir)?;
+        write_file(&dir.join(SERVER_CERT), &cert.pem(), false)?;
+        write_file(&dir.join(SERVER_KEY), &key.serialize_pem(), true)?;
+
+        Ok(dir)
+    }
+
+    /// The CA certificate is only ever written, so the issuer is rebuilt from its key
+    /// and the fixed parameters it was created with
+    fn issuer(&self) -> Result<Issuer<'static, KeyPair>> {
+        let key_path = self.dir.join(CA_KEY);
+        let params = ca_params();
+
+        if key_path.exists() && self.root_cert().exists() {
+            let pem = std::fs::read_to_string(&key_path)
+                .into_diagnostic()
+                .wrap_err_with(|| format!("Failed to read {}", key_path.display()))?;
+            let key = KeyPair::from_pem(&pem)
+                .map_err(|err| miette!("Invalid CA key {}: {err}", key_path.display()))?;
+            return Ok(Issuer::new(params, key));
+        }
+
+        let key = KeyPair::generate().into_diagnostic()?;
+        let cert = params.self_signed(&key).into_diagnostic()?;
+        create_dir(&self.dir)?;
+        write_file(&key_path, &key.serialize_pem(), true)?;
+        write_file(&self.root_cert(), &cert.pem(), false)?;
+
+        Ok(Issuer::new(params, key))
+    }
+}
+
+fn ca_params() -> CertificateParams {
+    let mut params = CertificateParams::default();
+    params.distinguished_name.push(DnType::CommonName, CA_NAME);
+    params.is_ca = IsCa::Ca(BasicConstraints::Unconstrained);
+    params.key_usages = vec![KeyUsagePurpose::KeyCertSign, KeyUsagePurpose::CrlSign];
+    params
+}
+
+/// Names the server certificate is valid for: loopback, and the address other
+/// machines connect through when listening beyond it
+pub fn server_hosts(bind: IpAddr) -> Vec<String> {
+    let mut hosts = vec![
+        "localhost".to_string(),
+        Ipv4Addr::LOCALHOST.to_string(),
+        Ipv6Addr::LOCALHOST.to_string(),
+    ];
+    if !bind.is_loopback() {
+        for host in [bind, ports::connect_host(bind)] {
+            let name = host.to_string();
+            if !host.is_unspecified() && !hosts.contains(&name) {
+                hosts.push(name);
+            }
+        }
+    }
+    hosts
+}
+
+fn create_dir(dir: &Path) -> Result<()> {
+    std::fs::create_dir_all(dir)
+        .into_diagnostic()
+        .wrap_err_with(|| format!("Failed to create {}", dir.display()))
+}
+
+/// Private files are created readable by the owner only, and restricted before anything
+/// is written when they already exist
+fn write_file(path: &Path, content: &str, private: bool) -> Result<()> {
+    use std::io::Write;
+
+    let mut options = std::fs::OpenOptions::new();
+    options.write(true).create(true).truncate(true);
+    #[cfg(unix)]
+    if private {
+        use std::os::unix::fs::OpenOptionsExt;
+
+        options.mode(0o600);
+    }
+
+    let mut file = options
+        .open(path)
+        .into_diagnostic()
+        .wrap_err_with(|| format!("Failed to write {}", path.display()))?;
+
+    #[cfg(unix)]
+    if private {
+        use std::os::unix::fs::PermissionsExt;
+
+        // `mode` only applies to newly created files
+        file.set_permissions(std::fs::Permissions::from_mode(0o600))
+            .into_diagnostic()
+            .wrap_err_with(|| format!("Failed to restrict access to {}", path.display()))?;
+    }
+    #[cfg(not(unix))]
+    let _ = private;
+
+    file.write_all(content.as_bytes())
+        .into_diagnostic()
+        .wrap_err_with(|| format!("Failed to write {}", path.display()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::LOOPBACK;
+
+    #[test]
+    fn test_reuses_ca_across_server_certs() {
+        let dir = std::env::temp_dir().join(format!("pgd-tls-{}", std::process::id()));
+        let ca = LocalCa::at(&dir);
+
+        let first = ca
+            .issue_server_cert("app", &server_hosts(LOOPBACK))
+            .unwrap();
+        let root = std::fs::read_to_string(ca.root_cert()).unwrap();
+        let second = ca
+            .issue_server_cert("api", &server_hosts(LOOPBACK))
+            .unwrap();
+
+        assert_eq!(std::fs::read_to_string(ca.root_cert()).unwrap(), root);
+        assert_ne!(first, second);
+        for dir in [first, second] {
+            assert!(dir.join(SERVER_CERT).is_file());
+            assert!(dir.join(SERVER_KEY).is_file());
+        }
+
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_keys_are_private() {
+        use std::os::unix::fs::PermissionsExt;
+
+        let dir = std::env::temp_dir().join(format!("pgd-tls-keys-{}", std::process::id()));
+        let ca = LocalCa::at(&dir);
+        let server_key = ca.server_dir("app").join(SERVER_KEY);
+        std::fs::create_dir_all(ca.server_dir("app")).unwrap();
+        std::fs::write(&server_key, "stale").unwrap();
+        std::fs::set_permissions(&server_key, std::fs::Permissions::from_mode(0o644)).unwrap();
+
+        ca.issue_server_cert("app", &server_hosts(LOOPBACK))
+            .unwrap();
+        let mode = |path: &Path| std::fs::metadata(path).unwrap().permissions().mode() & 0o777;
+
+        assert_eq!(mode(&dir.join(CA_KEY)), 0o600);
+        assert_eq!(mode(&server_key), 0o600);
+        assert_ne!(std::fs::read_to_string(&server_key).unwrap(), "stale");
+
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn test_server_hosts() {
+        assert_eq!(server_hosts(LOOPBACK), ["localhost", "127.0.0.1", "::1"]);
+
+        let lan: IpAddr = "192.168.1.20".parse().unwrap();
+        assert_eq!(server_hosts(lan).last().unwrap(), "192.168.1.20");
+        assert!(!server_hosts(Ipv4Addr::UNSPECIFIED.into()).contains(&"0.0.0.0".to_string()));
+    }
+}
@@ -0,0 +1,346 @@
+//! Failures of pgd, with the exit codes scripts can tell them apart by
+
+use std::path::{Path, PathBuf};
+
+use miette::{Diagnostic, LabeledSpan, NamedSource, SourceSpan};
+use thiserror::Error;
+
+use crate::{
+    config::{PostgresVersion, RuntimeKind},
+    controller::{crash, runtime::ContainerInfo},
+};
+
+/// Failures pgd can explain. Diagnostic codes are stable, so scripts can match on them
+#[derive(Error, Debug, Diagnostic)]
+pub enum Error {
+    #[error("This command requires project. Please, initiliaze a project.")]
+    #[diagnostic(
+        code(pgd::not_initialized),
+        help("Run `pgd init` in the project directory")
+    )]
+    ProjectRequired,
+
+    #[error(
+        "This command requires instance. Either initiliaze a project, or pass -I with instance name"
+    )]
+    #[diagnostic(
+        code(pgd::not_initialized),
+        help("Run `pgd instance start` to create the instance")
+    )]
+    InstanceRequired,
+
+    #[error("Failed to connect to {kind}! pgd requires it installed and running.")]
+    #[diagnostic(
+        code(pgd::daemon_unavailable),
+        help(
+            "Start the daemon, or point pgd to one with --docker-host or DOCKER_HOST. Podman needs its API socket enabled"
+        )
+    )]
+    DaemonUnavailable {
+        kind: RuntimeKind,
+        #[diagnostic_source]
+        cause: miette::Report,
+    },
+
+    #[error("Failed to pull {image}: access denied or no such image")]
+    #[diagnostic(
+        code(pgd::image_pull_denied),
+        help(
+            "Check that the version exists on Docker Hub, and that `docker login` credentials or registry mirrors are valid"
+        )
+    )]
+    ImagePullDenied {
+        image: String,
+        #[diagnostic_source]
+        cause: miette::Report,
+    },
+
+    #[error("Cannot move {container} to port {port}: port {port} is already in use")]
+    #[diagnostic(
+        code(pgd::port_in_use),
+        help("Free the port or pick another one in pgd.toml")
+    )]
+    PortInUse { container: String, port: u16 },
+
+    #[error("Cannot start {container}: port {port} is already taken by another program")]
+    #[diagnostic(
+        code(pgd::port_in_use),
+        help("Free the port or pick another one in pgd.toml")
+    )]
+    PortTaken {
+        container: String,
+        port: u16,
+        #[diagnostic_source]
+        cause: miette::Report,
+    },
+
+    #[error("Upgrades are currently unsupported! :(")]
+    #[diagnostic(
+        code(pgd::version_mismatch),
+        help(
+            "The instance runs {from}, pgd.toml asks for {to}. Dump the data and recreate the instance, or set version back to {from}"
+        )
+    )]
+    UpgradeUnsupported {
+        from: PostgresVersion,
+        to: PostgresVersion,
+    },
+
+    #[error("Cannot downgrade PostgreSQL from {from} to {to}. Downgrades are not supported.")]
+    #[diagnostic(
+        code(pgd::version_mismatch),
+        help("Set version in pgd.toml back to {from}")
+    )]
+    DowngradeUnsupported {
+        from: PostgresVersion,
+        to: PostgresVersion,
+    },
+
+    #[error("Container {container} keeps crashing ({status}), gave up after {attempts} attempts")]
+    #[diagnostic(code(pgd::crash_loop))]
+    CrashLoop {
+        container: String,
+        attempts: usize,
+        status: String,
+        #[help]
+        help: String,
+        /// Tail of the container log
+        #[source_code]
+        logs: NamedSource<String>,
+        #[label(collection)]
+        labels: Vec<LabeledSpan>,
+    },
+
+    #[error("Container {container} has no {label} label")]
+    #[diagnostic(
+        code(pgd::version_label_missing),
+        help("The container was not created by pgd. Remove it with `pgd instance destroy`")
+    )]
+    VersionLabelMissing {
+        container: String,
+        label: &'static str,
+    },
+
+    #[error("State file {} is corrupt", path.display())]
+    #[diagnostic(
+        code(pgd::state_corrupt),
+        help("Fix the file by hand, or move it away to start over. Containers are not affected")
+    )]
+    StateCorrupt {
+        path: PathBuf,
+        #[source_code]
+        src: NamedSource<String>,
+        #[label("{reason}")]
+        span: SourceSpan,
+        reason: String,
+    },
+
+    #[error(
+        "State file {} has schema version {version}, this pgd only knows up to {supported}",
+        path.display()
+    )]
+    #[diagnostic(
+        code(pgd::state_too_new),
+        help("It was written by a newer pgd. Upgrade pgd to use it")
+    )]
+    StateTooNew {
+        path: PathBuf,
+        version: u32,
+        supported: u32,
+    },
+
+    #[error("Failed to parse {}", path.display())]
+    #[diagnostic(code(pgd::config_invalid))]
+    ConfigInvalid {
+        path: PathBuf,
+        #[help]
+        help: String,
+        #[source_code]
+        src: NamedSource<String>,
+        #[label("{reason}")]
+        span: Option<SourceSpan>,
+        reason: String,
+    },
+
+    #[error("Refusing to {action} without confirmation")]
+    #[diagnostic(
+        code(pgd::confirmation_required),
+        help("Pass --force or --yes, or run pgd in a terminal to confirm")
+    )]
+    ConfirmationRequired { action: &'static str },
+}
+
+impl Error {
+    /// Attaches the log tail, pointing at the line that explains the crash when there is one
+    pub fn crash_loop(
+        container: String,
+        attempts: usize,
+        info: &ContainerInfo,
+        lines: &[String],
+    ) -> Self {
+        let logs = lines.join("\n");
+        let mut labels = Vec::new();
+
+        // spanning the whole tail, so all of it is shown rather than a few lines of context
+        if !logs.is_empty() {
+            labels.push(LabeledSpan::new_with_span(
+                Some("last lines of the container log".to_string()),
+                (0, logs.len()),
+            ));
+        }
+
+        let cause = crash::classify(info, lines);
+        if let Some((Some(index), cause)) = cause {
+            let offset = lines[..index].iter().map(|l| l.len() + 1).sum::<usize>();
+            labels.push(LabeledSpan::new_primary_with_span(
+                Some(cause.summary().to_string()),
+                (offset, lines[index].len()),
+            ));
+        }
+
+        let help = match cause {
+            Some((None, cause)) => format!("{}. {}", capitalize(cause.summary()), cause.help()),
+            Some((_, cause)) => cause.help().to_string(),
+            None => "See the full log with `pgd instance logs`".to_string(),
+        };
+
+        Self::CrashLoop {
+            status: crash::exit_status(info),
+            container: container.clone(),
+            attempts,
+            help,
+            logs: NamedSource::new(format!("{container} log"), logs),
+            labels,
+        }
+    }
+
+    /// Points at the line and column serde_json complained about
+    pub fn state_corrupt(path: &Path, content: String, err: serde_json::Error) -> Self {
+        let offset = content
+            .split_inclusive('\n')
+            .take(err.line().saturating_sub(1))
+            .map(str::len)
+            .sum::<usize>()
+            + err.column().saturating_sub(1);
+
+        Self::StateCorrupt {
+            path: path.to_path_buf(),
+            src: NamedSource::new(path.display().to_string(), content),
+            span: offset.into(),
+            reason: err.to_string(),
+        }
+    }
+
+    pub fn config_invalid(path: &Path, content: String, err: toml::de::Error) -> Self {
+        Self::invalid_toml(
+            path,
+            content,
+            err,
+            "Every project needs version, password, and port or socket_dir",
+        )
+    }
+
+    pub fn user_config_invalid(path: &Path, content: String, err: toml::de::Error) -> Self {
+        Self::invalid_toml(
+            path,
+            content,
+            err,
+            "Every setting is optional, see the README for the supported ones",
+        )
+    }
+
+    fn invalid_toml(path: &Path, content: String, err: toml::de::Error, help: &str) -> Self {
+        Self::ConfigInvalid {
+            path: path.to_path_buf(),
+            help: help.to_string(),
+            src: NamedSource::new(path.display().to_string(), content),
+            span: err.span().map(SourceSpan::from),
+            reason: err.message().to_string(),
+        }
+    }
+}
+
+fn capitalize(s: &str) -> String {
+    let mut chars = s.chars();
+    match chars.next() {
+        Some(first) => first.to_uppercase().chain(chars).collect(),
+        None => String::new(),
+    }
+}
+
+/// Exit codes of the pgd binary. Other failures exit with 1, and invalid arguments with 2
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ExitCode {
+    Failure = 1,
+    NotInitialized = 3,
+    DaemonUnavailable = 4,
+    PortConflict = 5,
+    VersionMismatch = 6,
+    StartFailed = 7,
+    ConfirmationRequired = 8,
+}
+
+impl ExitCode {
+    pub fn of(report: &miette::Report) -> Self {
+        let Some(error) = report.downcast_ref::<Error>() else {
+            return Self::Failure;
+        };
+
+        match error {
+            Error::ProjectRequired | Error::InstanceRequired => Self::NotInitialized,
+            Error::DaemonUnavailable { .. } => Self::DaemonUnavailable,
+            Error::PortInUse { .. } | Error::PortTaken { .. } => Self::PortConflict,
+            Error::UpgradeUnsupported { .. } | Error::DowngradeUnsupported { .. } => {
+                Self::VersionMismatch
+            }
+            Error::CrashLoop { .. } => Self::StartFailed,
+            Error::ConfirmationRequired { .. } => Self::ConfirmationRequired,
+            Error::ImagePullDenied { .. }
+            | Error::VersionLabelMissing { .. }
+            | Error::StateCorrupt { .. }
+            | Error::StateTooNew { .. }
+            | Error::ConfigInvalid { .. } => Self::Failure,
+        }
+    }
+}
+
+impl From<ExitCode> for i32 {
+    fn from(code: ExitCode) -> Self {
+        code as i32
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use miette::WrapErr;
+
+    use super::*;
+
+    #[test]
+    fn test_exit_code_through_context() {
+        let report: miette::Report =
+            Error::crash_loop("pgd-app-17_7".to_string(), 10, &Default::default(), &[]).into();
+        let report = Err::<(), _>(report)
+            .wrap_err("Failed to initialize project")
+            .unwrap_err();
+
+        assert_eq!(ExitCode::of(&report), ExitCode::StartFailed);
+        assert_eq!(
+            ExitCode::of(&miette::miette!("anything else")),
+            ExitCode::Failure
+        );
+    }
+
+    #[test]
+    fn test_state_corrupt_points_at_error() {
+        let content = "{\n  \"port\": ,\n}".to_string();
+        let err = serde_json::from_str::<serde_json::Value>(&content).unwrap_err();
+
+        let Error::StateCorrupt { span, .. } =
+            Error::state_corrupt(Path::new("state.json"), content.clone(), err)
+        else {
+            unreachable!()
+        };
+        assert_eq!(&content[span.offset()..span.offset() + 1], ",");
+    }
+}
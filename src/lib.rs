@@ -0,0 +1,159 @@
+//! Project-scoped PostgreSQL instances for local development.
+//!
+//! Besides the `pgd` CLI, instances can be managed from test harnesses:
+//!
+//! ```no_run
+//! # async fn example() -> miette::Result<()> {
+//! // instance described by the pgd.toml of a project, started if needed
+//! let instance = pgd::ensure_instance(".").await?;
+//! println!("{}", instance.dsn()?);
+//!
+//! // throwaway instance on tmpfs
+//! let db = pgd::ephemeral(Some("16")).await?;
+//! println!("{}", db.dsn());
+//! db.cleanup().await?;
+//! # Ok(())
+//! # }
+//! ```
+
+mod cli;
+mod config;
+mod consts;
+mod controller;
+mod error;
+mod paths;
+mod state;
+
+use std::path::{Path, PathBuf};
+
+use miette::{Result, miette};
+
+pub use crate::{
+    config::{PGDConfig, PostgresVersion, Project},
+    controller::{
+        Context, docker::DockerController, ephemeral::EphemeralInstance, reconciler::Reconciler,
+        runtime::ContainerRuntime,
+    },
+};
+
+use crate::cli::GlobalOptions;
+
+#[cfg(any(test, feature = "testing"))]
+pub use crate::controller::runtime::fake::FakeRuntime;
+
+/// Running instance of a project
+#[derive(Debug, Clone)]
+pub struct Instance {
+    project: Project,
+    root_cert: Option<PathBuf>,
+}
+
+impl Instance {
+    pub fn project(&self) -> &Project {
+        &self.project
+    }
+
+    /// `None` when the instance is only reachable through its socket
+    pub fn port(&self) -> Option<u16> {
+        self.project.config.port
+    }
+
+    pub fn password(&self) -> &str {
+        &self.project.config.password
+    }
+
+    /// Over TCP when a port is published, through the socket directory otherwise.
+    /// Fails for a project with neither, which `pgd.toml` can't describe
+    pub fn dsn(&self) -> Result<String> {
+        let config = &self.project.config;
+        match (config.port, self.project.socket_path()) {
+            (Some(port), _) => {
+                let dsn = controller::utils::format_dsn(
+                    controller::ports::connect_host(config.bind_address()),
+                    &config.password,
+                    port,
+                );
+                Ok(match &self.root_cert {
+                    Some(root_cert) => controller::utils::with_tls(dsn, root_cert),
+                    None => dsn,
+                })
+            }
+            (None, Some(dir)) => Ok(controller::utils::format_socket_dsn(&dir, &config.password)),
+            (None, None) => Err(miette!(
+                "{} has neither a port nor a socket directory",
+                self.project.container_name()
+            )),
+        }
+    }
+}
+
+/// Entry point of the `pgd` binary, not part of the library API
+#[doc(hidden)]
+pub async fn cli_main() -> Result<()> {
+    cli::main().await
+}
+
+/// Bring the instance of the project at `path` in line with its pgd.toml,
+/// the same way `pgd instance start` does
+pub async fn ensure_instance(path: impl AsRef<Path>) -> Result<Instance> {
+    let path = path.as_ref();
+    let ctx = Context::at(path).await?;
+    let project = ctx
+        .project()
+        .cloned()
+        .ok_or_else(|| miette!("No pgd.toml found in {}", path.display()))?;
+
+    Reconciler { ctx: &ctx }.reconcile(&project).await?;
+
+    let root_cert = ctx.root_cert(&project)?;
+    Ok(Instance { project, root_cert })
+}
+
+/// Start a throwaway instance, `version` being either a major (`16`) or an exact (`16.11`) one.
+/// Like `pgd ephemeral`, defaults and the registry mirror come from the user config
+pub async fn ephemeral(version: Option<&str>) -> Result<EphemeralInstance> {
+    let ctx = Context::detached(&GlobalOptions::default()).await?;
+    let version = version.or(ctx.user_config().default_version.as_deref());
+    let instance = EphemeralInstance::create(ctx.runtime().clone(), version).await?;
+
+    if let Err(err) = instance.start().await {
+        instance.cleanup().await?;
+        return Err(err);
+    }
+
+    Ok(instance)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn instance(port: Option<u16>, socket_dir: Option<&str>) -> Instance {
+        Instance {
+            project: Project {
+                name: "app".to_string(),
+                path: PathBuf::from("/home/user/app"),
+                config: PGDConfig {
+                    port,
+                    socket_dir: socket_dir.map(PathBuf::from),
+                    ..PGDConfig::for_test("17.7", 5432)
+                },
+            },
+            root_cert: None,
+        }
+    }
+
+    #[test]
+    fn test_dsn_without_port() {
+        let tcp = instance(Some(5432), Some(".pgd/socket")).dsn().unwrap();
+        let socket = instance(None, Some(".pgd/socket")).dsn().unwrap();
+        let neither = instance(None, None).dsn();
+
+        assert!(tcp.contains("127.0.0.1:5432"));
+        assert_eq!(
+            socket,
+            "postgres://postgres:secret@/postgres?host=/home/user/app/.pgd/socket"
+        );
+        assert!(neither.is_err());
+    }
+}
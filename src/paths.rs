@@ -0,0 +1,208 @@
+//! Where pgd keeps its own files, following the XDG base directory spec
+
+use std::path::{Path, PathBuf};
+
+use miette::{Context, IntoDiagnostic, Result};
+use tracing::info;
+
+/// Overrides every other location, e.g. to isolate CI runs
+pub const PGD_HOME_ENV: &str = "PGD_HOME";
+
+/// Directories before XDG support, relative to `$HOME`
+const LEGACY_DIR: &str = ".pgd";
+
+const CONFIG_FILE: &str = "config.toml";
+
+/// Variables the locations are resolved from
+#[derive(Debug, Default)]
+struct Env {
+    pgd_home: Option<PathBuf>,
+    xdg_state_home: Option<PathBuf>,
+    xdg_config_home: Option<PathBuf>,
+    local_app_data: Option<PathBuf>,
+    home: Option<PathBuf>,
+}
+
+impl Env {
+    fn current() -> Self {
+        // relative values are invalid per spec and ignored
+        let var = |name: &str| {
+            std::env::var_os(name)
+                .map(PathBuf::from)
+                .filter(|path| path.is_absolute())
+        };
+
+        Self {
+            pgd_home: var(PGD_HOME_ENV),
+            xdg_state_home: var("XDG_STATE_HOME"),
+            xdg_config_home: var("XDG_CONFIG_HOME"),
+            local_app_data: var("LOCALAPPDATA").filter(|_| cfg!(windows)),
+            home: std::env::home_dir(),
+        }
+    }
+
+    fn home(&self) -> Result<&Path> {
+        self.home
+            .as_deref()
+            .wrap_err("Failed to get HOME environment variable")
+    }
+
+    /// `$PGD_HOME`, `$XDG_STATE_HOME/pgd` or `~/.local/state/pgd`
+    fn state_dir(&self) -> Result<PathBuf> {
+        if let Some(dir) = &self.pgd_home {
+            return Ok(dir.clone());
+        }
+        if let Some(dir) = &self.xdg_state_home {
+            return Ok(dir.join("pgd"));
+        }
+        if let Some(dir) = &self.local_app_data {
+            return Ok(dir.join("pgd").join("state"));
+        }
+        Ok(self.home()?.join(".local").join("state").join("pgd"))
+    }
+
+    /// `$PGD_HOME`, `$XDG_CONFIG_HOME/pgd` or `~/.config/pgd`
+    fn config_dir(&self) -> Result<PathBuf> {
+        if let Some(dir) = &self.pgd_home {
+            return Ok(dir.clone());
+        }
+        if let Some(dir) = &self.xdg_config_home {
+            return Ok(dir.join("pgd"));
+        }
+        if let Some(dir) = &self.local_app_data {
+            return Ok(dir.join("pgd").join("config"));
+        }
+        Ok(self.home()?.join(".config").join("pgd"))
+    }
+}
+
+/// Instance state and its lock and backups
+pub fn state_dir() -> Result<PathBuf> {
+    Env::current().state_dir()
+}
+
+/// User-level defaults, written by hand
+pub fn config_file() -> Result<PathBuf> {
+    Ok(Env::current().config_dir()?.join(CONFIG_FILE))
+}
+
+/// `~/.pgd`, if there still is one to migrate. Never used with `PGD_HOME` set
+pub(crate) fn legacy_dir() -> Option<PathBuf> {
+    let env = Env::current();
+    if env.pgd_home.is_some() {
+        return None;
+    }
+
+    env.home
+        .map(|home| home.join(LEGACY_DIR))
+        .filter(|dir| dir.is_dir())
+}
+
+/// Moves the files of the legacy directory into `dir`, and removes it when empty.
+/// Files already present in `dir` are left alone, except for the lock file
+pub(crate) fn migrate_legacy_dir(legacy: &Path, dir: &Path) -> Result<()> {
+    std::fs::create_dir_all(dir)
+        .into_diagnostic()
+        .wrap_err_with(|| format!("Failed to create {}", dir.display()))?;
+
+    let entries = std::fs::read_dir(legacy)
+        .into_diagnostic()
+        .wrap_err_with(|| format!("Failed to read {}", legacy.display()))?;
+
+    for entry in entries {
+        let from = entry.into_diagnostic()?.path();
+        let Some(name) = from.file_name() else {
+            continue;
+        };
+        let to = dir.join(name);
+        if !from.is_file() {
+            continue;
+        }
+        // the lock is held in the new directory already
+        if from.extension().is_some_and(|ext| ext == "lock") {
+            let _ = std::fs::remove_file(&from);
+            continue;
+        }
+        if to.exists() {
+            continue;
+        }
+
+        // across filesystems, rename fails and the file has to be copied
+        if std::fs::rename(&from, &to).is_err() {
+            std::fs::copy(&from, &to)
+                .and_then(|_| std::fs::remove_file(&from))
+                .into_diagnostic()
+                .wrap_err_with(|| {
+                    format!("Failed to move {} to {}", from.display(), to.display())
+                })?;
+        }
+    }
+
+    // fails while anything is left, which is fine
+    if std::fs::remove_dir(legacy).is_ok() {
+        info!(
+            "Moved pgd state from {} to {}",
+            legacy.display(),
+            dir.display()
+        );
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_resolve_dirs() {
+        let env = Env {
+            home: Some(PathBuf::from("/home/user")),
+            ..Default::default()
+        };
+        assert_eq!(
+            env.state_dir().unwrap(),
+            PathBuf::from("/home/user/.local/state/pgd")
+        );
+        assert_eq!(
+            env.config_dir().unwrap(),
+            PathBuf::from("/home/user/.config/pgd")
+        );
+
+        let env = Env {
+            xdg_state_home: Some(PathBuf::from("/xdg/state")),
+            xdg_config_home: Some(PathBuf::from("/xdg/config")),
+            ..env
+        };
+        assert_eq!(env.state_dir().unwrap(), PathBuf::from("/xdg/state/pgd"));
+        assert_eq!(env.config_dir().unwrap(), PathBuf::from("/xdg/config/pgd"));
+
+        let env = Env {
+            pgd_home: Some(PathBuf::from("/ci/pgd")),
+            ..env
+        };
+        assert_eq!(env.state_dir().unwrap(), PathBuf::from("/ci/pgd"));
+        assert_eq!(env.config_dir().unwrap(), PathBuf::from("/ci/pgd"));
+    }
+
+    #[test]
+    fn test_migrate_legacy_dir() {
+        let root = std::env::temp_dir().join(format!("pgd-legacy-{}", std::process::id()));
+        let legacy = root.join(".pgd");
+        let dir = root.join("state").join("pgd");
+        std::fs::create_dir_all(&legacy).unwrap();
+        std::fs::write(legacy.join("state.json"), "{}").unwrap();
+        std::fs::write(legacy.join("state.json.bak"), "{}").unwrap();
+        std::fs::write(legacy.join("state.json.lock"), "").unwrap();
+
+        migrate_legacy_dir(&legacy, &dir).unwrap();
+        let moved = std::fs::read_to_string(dir.join("state.json")).unwrap();
+        let backup_moved = dir.join("state.json.bak").exists();
+        let legacy_left = legacy.exists();
+        std::fs::remove_dir_all(&root).unwrap();
+
+        assert_eq!(moved, "{}");
+        assert!(backup_moved);
+        assert!(!legacy_left);
+    }
+}
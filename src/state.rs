@@ -1,10 +1,21 @@
 use miette::{Context, IntoDiagnostic, Result};
+use parking_lot::Mutex;
 use serde::{Deserialize, Serialize};
-use std::cell::RefCell;
-use std::collections::HashMap;
-use std::path::PathBuf;
+use serde_json::{Value, json};
+use std::collections::{BTreeSet, HashMap};
+use std::fs::File;
+use std::net::{IpAddr, SocketAddr};
+use std::path::{Path, PathBuf};
+use tracing::{info, warn};
 
-use crate::config::PostgresVersion;
+use crate::config::{DataStorage, LOOPBACK, Limits, Listen, PostgresVersion};
+use crate::error::Error;
+use crate::paths;
+
+const STATE_FILE: &str = "state.json";
+
+/// Reservations of projects that never got an instance, e.g. after a failed init, expire
+const RESERVATION_TTL_SECS: u64 = 60 * 60;
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct InstanceState {
@@ -12,109 +23,653 @@ pub struct InstanceState {
 
     pub postgres_version: PostgresVersion,
 
-    pub port: u16,
+    /// `None` for instances only reachable through their socket. Older files always
+    /// have a number here, which still parses, so going optional needed no migration
+    pub port: Option<u16>,
 
     pub created_at: u64,
+
+    /// Fingerprint of the password currently applied to the database role
+    #[serde(default)]
+    pub password_fingerprint: Option<String>,
+
+    /// Named volume holding PGDATA. Missing for instances created with an anonymous volume
+    #[serde(default)]
+    pub volume_name: Option<String>,
+
+    /// Host directory holding PGDATA, when the project sets `data_dir`
+    #[serde(default)]
+    pub data_dir: Option<PathBuf>,
+
+    /// Daemon the container was created on. Missing for instances created before it was recorded
+    #[serde(default)]
+    pub daemon_host: Option<String>,
+
+    /// Host address the port is published on. Missing for instances created before it
+    /// was configurable, which listen on 127.0.0.1
+    #[serde(default)]
+    pub bind: Option<IpAddr>,
+
+    /// Host directory the socket directory is mounted to, when the project sets `socket_dir`
+    #[serde(default)]
+    pub socket_dir: Option<PathBuf>,
+
+    /// Host directory of the server certificate, when the project sets `tls`
+    #[serde(default)]
+    pub tls_dir: Option<PathBuf>,
+
+    /// Resource limits the container was created with
+    #[serde(default)]
+    pub limits: Limits,
 }
 
-#[derive(Debug, Clone, Serialize, Deserialize, Default)]
+/// Port handed out to a project that has no instance yet
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct PortReservation {
+    pub port: u16,
+
+    pub reserved_at: u64,
+}
+
+/// Upgrades the state file from the version of its index to the next one
+type Migration = fn(&mut Value);
+
+/// Append only. Files without `schema_version` are version 0.
+///
+/// Only changes that break reading older files, like a renamed key or a new key scheme,
+/// need a migration. Fields added with `#[serde(default)]` and fields widened to accept
+/// more, like `port` becoming optional, don't: older files still parse. Older releases
+/// can read such files too, as long as the entries don't use the new values, while
+/// bumping the version would make them refuse every file. As a consequence, migrations
+/// can't rely on optional keys being present in the files they upgrade
+const MIGRATIONS: &[Migration] = &[migrate_v0_to_v1];
+
+const SCHEMA_VERSION: u32 = MIGRATIONS.len() as u32;
+
+/// Stamps the first schema version, writing out the optional fields of its time
+fn migrate_v0_to_v1(state: &mut Value) {
+    const OPTIONAL_FIELDS: &[&str] = &[
+        "password_fingerprint",
+        "volume_name",
+        "data_dir",
+        "daemon_host",
+    ];
+
+    let instances = state
+        .as_object_mut()
+        .map(|state| state.entry("instances").or_insert_with(|| json!({})));
+    let Some(Value::Object(instances)) = instances else {
+        return;
+    };
+
+    for instance in instances.values_mut().filter_map(Value::as_object_mut) {
+        for field in OPTIONAL_FIELDS {
+            instance.entry(*field).or_insert(Value::Null);
+        }
+    }
+}
+
+fn migrate(state: &mut Value, from: u32) {
+    for (version, migration) in MIGRATIONS.iter().enumerate().skip(from as usize) {
+        migration(state);
+        state["schema_version"] = (version as u32 + 1).into();
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
 struct State {
+    #[serde(default)]
+    schema_version: u32,
     #[serde(default)]
     instances: HashMap<String, InstanceState>,
+    #[serde(default)]
+    reservations: HashMap<String, PortReservation>,
 }
+
+impl Default for State {
+    fn default() -> Self {
+        Self {
+            schema_version: SCHEMA_VERSION,
+            instances: HashMap::new(),
+            reservations: HashMap::new(),
+        }
+    }
+}
+
 impl State {
-    fn new() -> Result<Self> {
-        let state_path = state_file_path()?;
+    /// Ports of instances, running or not, and live reservations, except those of `project`
+    fn taken_ports(&self, project: &str, now: u64) -> BTreeSet<u16> {
+        let instances = self
+            .instances
+            .iter()
+            .filter(|(name, _)| *name != project)
+            .filter_map(|(_, instance)| instance.port);
+        let reservations = self
+            .reservations
+            .iter()
+            .filter(|(name, reservation)| {
+                *name != project && now < reservation.reserved_at + RESERVATION_TTL_SECS
+            })
+            .map(|(_, reservation)| reservation.port);
 
-        if !state_path.exists() {
-            if let Some(parent) = state_path.parent() {
-                std::fs::create_dir_all(parent)
-                    .into_diagnostic()
-                    .wrap_err("Failed to create .pgd directory")?;
-            }
+        instances.chain(reservations).collect()
+    }
 
+    /// Falls back to the backup of the last successful save when the file does not parse.
+    /// Must be called with the state lock held
+    fn load(state_path: &Path) -> Result<Self> {
+        if !state_path.exists() {
             return Ok(Self::default());
         }
 
-        let content = std::fs::read_to_string(&state_path)
-            .into_diagnostic()
-            .wrap_err_with(|| format!("Failed to read state file: {}", state_path.display()))?;
+        let content = read(state_path)?;
+        let err = match Self::parse(state_path, &content) {
+            Ok(state) => return Ok(state),
+            Err(err) => err,
+        };
+        if !matches!(err.downcast_ref(), Some(Error::StateCorrupt { .. })) {
+            return Err(err);
+        }
 
-        let state: Self = serde_json::from_str(&content)
-            .into_diagnostic()
-            .wrap_err("Failed to parse state.json")?;
+        let backup_path = sibling(state_path, "bak");
+        let Some(state) = std::fs::read_to_string(&backup_path)
+            .ok()
+            .and_then(|backup| Self::parse(state_path, &backup).ok())
+        else {
+            return Err(err);
+        };
+
+        warn!(
+            "State file {} is corrupt, restoring it from {}",
+            state_path.display(),
+            backup_path.display()
+        );
+        state.save(state_path)?;
 
         Ok(state)
     }
 
-    fn save(&self) -> Result<()> {
-        let state_path = state_file_path()?;
+    /// Older files are migrated and written back, keeping the original next to them
+    fn parse(state_path: &Path, content: &str) -> Result<Self> {
+        let corrupt = |err| Error::state_corrupt(state_path, content.to_string(), err);
 
-        if let Some(parent) = state_path.parent() {
-            std::fs::create_dir_all(parent)
-                .into_diagnostic()
-                .wrap_err("Failed to create .pgd directory")?;
+        let mut value: Value = serde_json::from_str(content).map_err(corrupt)?;
+        let version = value
+            .get("schema_version")
+            .and_then(Value::as_u64)
+            .unwrap_or(0) as u32;
+
+        if version > SCHEMA_VERSION {
+            return Err(Error::StateTooNew {
+                path: state_path.to_path_buf(),
+                version,
+                supported: SCHEMA_VERSION,
+            }
+            .into());
         }
+        if version == SCHEMA_VERSION {
+            return Ok(serde_json::from_str(content).map_err(corrupt)?);
+        }
+
+        migrate(&mut value, version);
+        let state: Self = serde_json::from_value(value).map_err(corrupt)?;
 
+        let backup_path = sibling(state_path, &format!("v{version}.bak"));
+        write_atomic(&backup_path, content)?;
+        state.save(state_path)?;
+        info!(
+            "Migrated state file from schema version {version} to {SCHEMA_VERSION}, previous one kept in {}",
+            backup_path.display()
+        );
+
+        Ok(state)
+    }
+
+    /// Must be called with the state lock held
+    fn save(&self, state_path: &Path) -> Result<()> {
         let content = serde_json::to_string_pretty(self)
             .into_diagnostic()
             .wrap_err("Failed to serialize state")?;
 
-        std::fs::write(&state_path, content)
-            .into_diagnostic()
-            .wrap_err_with(|| format!("Failed to write state file: {}", state_path.display()))?;
+        write_atomic(state_path, &content)?;
+        write_atomic(&sibling(state_path, "bak"), &content)?;
 
         Ok(())
     }
 }
 
-pub struct StateManager(RefCell<State>);
+/// Exclusive advisory lock serializing pgd processes around the state file,
+/// released when dropped
+struct StateLock {
+    _file: File,
+}
+
+impl StateLock {
+    fn acquire(state_path: &Path) -> Result<Self> {
+        if let Some(parent) = state_path.parent() {
+            std::fs::create_dir_all(parent)
+                .into_diagnostic()
+                .wrap_err("Failed to create state directory")?;
+        }
+
+        let lock_path = sibling(state_path, "lock");
+        let file = File::options()
+            .create(true)
+            .truncate(false)
+            .write(true)
+            .open(&lock_path)
+            .into_diagnostic()
+            .wrap_err_with(|| format!("Failed to open lock file: {}", lock_path.display()))?;
+        file.lock()
+            .into_diagnostic()
+            .wrap_err_with(|| format!("Failed to lock {}", lock_path.display()))?;
+
+        Ok(Self { _file: file })
+    }
+}
+
+fn read(path: &Path) -> Result<String> {
+    std::fs::read_to_string(path)
+        .into_diagnostic()
+        .wrap_err_with(|| format!("Failed to read state file: {}", path.display()))
+}
+
+/// Readers see either the old or the new file, never a partial write
+fn write_atomic(path: &Path, content: &str) -> Result<()> {
+    use std::io::Write;
+
+    let tmp_path = sibling(path, &format!("tmp.{}", std::process::id()));
+    let write = || -> std::io::Result<()> {
+        let mut file = File::create(&tmp_path)?;
+        file.write_all(content.as_bytes())?;
+        file.sync_all()?;
+        std::fs::rename(&tmp_path, path)
+    };
+
+    write()
+        .inspect_err(|_| {
+            let _ = std::fs::remove_file(&tmp_path);
+        })
+        .into_diagnostic()
+        .wrap_err_with(|| format!("Failed to write state file: {}", path.display()))
+}
+
+/// `state.json` -> `state.json.<ext>`
+fn sibling(path: &Path, ext: &str) -> PathBuf {
+    let mut name = path.file_name().unwrap_or_default().to_os_string();
+    name.push(".");
+    name.push(ext);
+    path.with_file_name(name)
+}
+
+pub struct StateManager {
+    state: Mutex<State>,
+    /// Upserts (`Some`) and removals (`None`) since the last save, replayed
+    /// on top of the file so entries written by other processes are kept
+    changes: Mutex<HashMap<String, Option<InstanceState>>>,
+    /// `None` keeps the state in memory only
+    path: Option<PathBuf>,
+}
 
 impl StateManager {
+    /// State in [`paths::state_dir`], taking over the legacy `~/.pgd` on first use
     pub fn new() -> Result<Self> {
-        Ok(Self(RefCell::new(State::new()?)))
+        let path = paths::state_dir()?.join(STATE_FILE);
+
+        if let Some(legacy) = paths::legacy_dir()
+            && !path.exists()
+        {
+            let _lock = StateLock::acquire(&path)?;
+            if !path.exists() {
+                paths::migrate_legacy_dir(&legacy, path.parent().unwrap())?;
+            }
+        }
+
+        Self::at(path)
+    }
+
+    fn at(path: PathBuf) -> Result<Self> {
+        let state = {
+            let _lock = StateLock::acquire(&path)?;
+            State::load(&path)?
+        };
+
+        Ok(Self {
+            state: Mutex::new(state),
+            changes: Mutex::new(HashMap::new()),
+            path: Some(path),
+        })
     }
 
+    /// State that is never written to disk, for tests and throwaway contexts
+    pub fn in_memory() -> Self {
+        Self {
+            state: Mutex::new(State::default()),
+            changes: Mutex::new(HashMap::new()),
+            path: None,
+        }
+    }
+
+    /// Reloads the file under the lock and applies the changes made since the last save
     pub fn save(&self) -> Result<()> {
-        self.0.borrow().save()?;
+        self.update(|_| Ok(()))
+    }
+
+    /// Picks a port for `project` with `pick`, given the ports other projects own, and
+    /// reserves it until the project gets an instance. Done under the lock, so
+    /// concurrent pgd processes never pick the same port
+    pub fn reserve_port(
+        &self,
+        project: &str,
+        pick: impl FnOnce(&BTreeSet<u16>) -> Result<u16>,
+    ) -> Result<u16> {
+        let now = now();
+        let mut port = 0;
+        self.update(|state| {
+            state
+                .reservations
+                .retain(|_, reservation| now < reservation.reserved_at + RESERVATION_TTL_SECS);
+            port = pick(&state.taken_ports(project, now))?;
+            state.reservations.insert(
+                project.to_string(),
+                PortReservation {
+                    port,
+                    reserved_at: now,
+                },
+            );
+            Ok(())
+        })?;
+
+        Ok(port)
+    }
+
+    /// Applies the pending changes and `f` to the latest state, and writes it
+    fn update(&self, f: impl FnOnce(&mut State) -> Result<()>) -> Result<()> {
+        let apply = |state: &mut State, changes: &mut HashMap<String, Option<InstanceState>>| {
+            for (name, change) in changes.drain() {
+                // the project has its port now
+                state.reservations.remove(&name);
+                match change {
+                    Some(instance) => state.instances.insert(name, instance),
+                    None => state.instances.remove(&name),
+                };
+            }
+        };
+
+        let Some(path) = &self.path else {
+            let mut state = self.state.lock();
+            apply(&mut state, &mut self.changes.lock());
+            return f(&mut state);
+        };
+
+        let _lock = StateLock::acquire(path)?;
+        let mut state = State::load(path)?;
+        apply(&mut state, &mut self.changes.lock());
+        f(&mut state)?;
+        state.save(path)?;
+        *self.state.lock() = state;
+
         Ok(())
     }
 
+    /// `None` for in-memory state
+    pub fn path(&self) -> Option<&Path> {
+        self.path.as_deref()
+    }
+
     pub fn get(&self, project_name: &str) -> Option<InstanceState> {
-        self.0.borrow().instances.get(project_name).cloned()
+        self.state.lock().instances.get(project_name).cloned()
     }
 
     pub fn upsert(&self, project_name: String, state: InstanceState) {
-        self.0.borrow_mut().instances.insert(project_name, state);
+        self.changes
+            .lock()
+            .insert(project_name.clone(), Some(state.clone()));
+        self.state.lock().instances.insert(project_name, state);
     }
 
     pub fn remove(&self, project_name: &str) -> Option<InstanceState> {
-        self.0.borrow_mut().instances.remove(project_name)
+        self.changes.lock().insert(project_name.to_string(), None);
+        self.state.lock().instances.remove(project_name)
     }
 
-    pub fn get_highest_used_port(&self) -> Option<u16> {
-        self.0.borrow().instances.values().map(|i| i.port).max()
+    /// All instances, ordered by project name
+    pub fn instances(&self) -> Vec<(String, InstanceState)> {
+        let mut instances: Vec<_> = self
+            .state
+            .lock()
+            .instances
+            .iter()
+            .map(|(name, instance)| (name.clone(), instance.clone()))
+            .collect();
+        instances.sort_by(|a, b| a.0.cmp(&b.0));
+        instances
+    }
+
+    /// Ports other projects own, see [`StateManager::reserve_port`]
+    pub fn taken_ports(&self, project: &str) -> BTreeSet<u16> {
+        self.state.lock().taken_ports(project, now())
     }
 }
 
-impl InstanceState {
-    pub fn new(container_id: String, postgres_version: PostgresVersion, port: u16) -> Self {
-        let now = std::time::SystemTime::now()
-            .duration_since(std::time::UNIX_EPOCH)
-            .unwrap()
-            .as_secs();
+fn now() -> u64 {
+    std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .unwrap()
+        .as_secs()
+}
 
-        InstanceState {
+impl InstanceState {
+    pub fn new(
+        container_id: String,
+        postgres_version: PostgresVersion,
+        listen: &Listen,
+        limits: Limits,
+        password_fingerprint: Option<String>,
+        storage: DataStorage,
+        daemon_host: String,
+    ) -> Self {
+        let mut state = InstanceState {
             container_id,
             postgres_version,
-            port,
-            created_at: now,
+            port: None,
+            created_at: now(),
+            password_fingerprint,
+            volume_name: None,
+            data_dir: None,
+            daemon_host: Some(daemon_host),
+            bind: None,
+            socket_dir: None,
+            tls_dir: None,
+            limits,
+        };
+        state.set_storage(storage);
+        state.set_listen(listen);
+        state
+    }
+
+    pub fn bind_address(&self) -> IpAddr {
+        self.bind.unwrap_or(LOOPBACK)
+    }
+
+    pub fn listen(&self) -> Listen {
+        Listen {
+            tcp: self
+                .port
+                .map(|port| SocketAddr::new(self.bind_address(), port)),
+            socket_dir: self.socket_dir.clone(),
+            tls: self.tls_dir.clone(),
+        }
+    }
+
+    pub fn set_listen(&mut self, listen: &Listen) {
+        self.port = listen.tcp.map(|address| address.port());
+        self.bind = listen.tcp.map(|address| address.ip());
+        self.socket_dir = listen.socket_dir.clone();
+        self.tls_dir = listen.tls.clone();
+    }
+
+    /// Recorded PGDATA storage, `None` for instances relying on an anonymous volume
+    pub fn storage(&self) -> Option<DataStorage> {
+        match (&self.data_dir, &self.volume_name) {
+            (Some(dir), _) => Some(DataStorage::HostDir(dir.clone())),
+            (None, Some(volume)) => Some(DataStorage::Volume(volume.clone())),
+            (None, None) => None,
+        }
+    }
+
+    pub fn set_storage(&mut self, storage: DataStorage) {
+        match storage {
+            DataStorage::Volume(volume) => {
+                self.volume_name = Some(volume);
+                self.data_dir = None;
+            }
+            DataStorage::HostDir(dir) => {
+                self.volume_name = None;
+                self.data_dir = Some(dir);
+            }
         }
     }
 }
 
-fn state_file_path() -> Result<PathBuf> {
-    let home = std::env::home_dir().wrap_err("Failed to get HOME environment variable")?;
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn instance(port: u16) -> InstanceState {
+        InstanceState::new(
+            format!("container-{port}"),
+            "17.7".parse().unwrap(),
+            &Listen {
+                tcp: Some(SocketAddr::new(LOOPBACK, port)),
+                ..Default::default()
+            },
+            Limits::default(),
+            None,
+            DataStorage::Volume(format!("volume-{port}")),
+            "fake://local".to_string(),
+        )
+    }
+
+    fn temp_state_path(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("pgd-{name}-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        dir.join("state.json")
+    }
+
+    /// A state file as written by each schema version
+    fn fixture(version: u32) -> &'static str {
+        match version {
+            0 => include_str!("state/fixtures/v0.json"),
+            1 => include_str!("state/fixtures/v1.json"),
+            _ => panic!("no fixture for schema version {version}"),
+        }
+    }
+
+    #[test]
+    fn test_migrations_match_fixtures() {
+        for from in 0..SCHEMA_VERSION {
+            let mut state: Value = serde_json::from_str(fixture(from)).unwrap();
+            MIGRATIONS[from as usize](&mut state);
+            state["schema_version"] = (from + 1).into();
+
+            let expected: Value = serde_json::from_str(fixture(from + 1)).unwrap();
+            assert_eq!(state, expected, "migration from version {from}");
+        }
+    }
 
-    Ok(home.join(".pgd").join("state.json"))
+    #[test]
+    fn test_upgrades_old_state_file_in_place() {
+        let path = temp_state_path("state-migrate");
+        std::fs::create_dir_all(path.parent().unwrap()).unwrap();
+        std::fs::write(&path, fixture(0)).unwrap();
+
+        let state = StateManager::at(path.clone()).unwrap();
+        let upgraded: Value =
+            serde_json::from_str(&std::fs::read_to_string(&path).unwrap()).unwrap();
+        let backup = std::fs::read_to_string(sibling(&path, "v0.bak")).unwrap();
+
+        std::fs::write(&path, r#"{"schema_version": 999, "instances": {}}"#).unwrap();
+        let too_new = StateManager::at(path.clone()).err();
+        let untouched = std::fs::read_to_string(&path).unwrap();
+        std::fs::remove_dir_all(path.parent().unwrap()).unwrap();
+
+        assert_eq!(
+            state.get("api").unwrap().volume_name.as_deref(),
+            Some("pgd-api-data")
+        );
+        assert_eq!(upgraded["schema_version"], SCHEMA_VERSION);
+        assert_eq!(backup, fixture(0));
+        assert!(too_new.unwrap().to_string().contains("schema version 999"));
+        assert!(untouched.contains("999"));
+    }
+
+    #[test]
+    fn test_concurrent_managers_keep_each_others_entries() {
+        let path = temp_state_path("state-concurrent");
+        let first = StateManager::at(path.clone()).unwrap();
+        let second = StateManager::at(path.clone()).unwrap();
+
+        first.upsert("app".to_string(), instance(5432));
+        second.upsert("api".to_string(), instance(5433));
+        first.save().unwrap();
+        second.save().unwrap();
+        second.remove("api");
+        second.save().unwrap();
+
+        let state = StateManager::at(path.clone()).unwrap();
+        std::fs::remove_dir_all(path.parent().unwrap()).unwrap();
+
+        assert_eq!(state.get("app").unwrap().port, Some(5432));
+        assert!(state.get("api").is_none());
+        assert_eq!(second.get("app").unwrap().port, Some(5432));
+    }
+
+    #[test]
+    fn test_reserved_ports_are_never_handed_out_twice() {
+        let path = temp_state_path("state-reserve");
+        let first = StateManager::at(path.clone()).unwrap();
+        let second = StateManager::at(path.clone()).unwrap();
+        first.upsert("stopped".to_string(), instance(5432));
+        first.save().unwrap();
+
+        let lowest_free =
+            |taken: &BTreeSet<u16>| Ok((5432..).find(|p| !taken.contains(p)).unwrap());
+        let app = first.reserve_port("app", lowest_free).unwrap();
+        let api = second.reserve_port("api", lowest_free).unwrap();
+        let again = first.reserve_port("app", lowest_free).unwrap();
+
+        first.upsert("app".to_string(), instance(app));
+        first.save().unwrap();
+        let state = StateManager::at(path.clone()).unwrap();
+        std::fs::remove_dir_all(path.parent().unwrap()).unwrap();
+
+        assert_eq!((app, api, again), (5433, 5434, 5433));
+        assert_eq!(
+            state.taken_ports("web").into_iter().collect::<Vec<_>>(),
+            vec![5432, 5433, 5434]
+        );
+        assert!(!state.state.lock().reservations.contains_key("app"));
+    }
+
+    #[test]
+    fn test_restores_corrupt_state_from_backup() {
+        let path = temp_state_path("state-backup");
+        let state = StateManager::at(path.clone()).unwrap();
+        state.upsert("app".to_string(), instance(5432));
+        state.save().unwrap();
+
+        std::fs::write(&path, "{\"instances\": {\"app\": ").unwrap();
+        let restored = StateManager::at(path.clone()).unwrap();
+        let content = std::fs::read_to_string(&path).unwrap();
+
+        std::fs::remove_file(sibling(&path, "bak")).unwrap();
+        std::fs::write(&path, "{").unwrap();
+        let corrupt = StateManager::at(path.clone());
+        std::fs::remove_dir_all(path.parent().unwrap()).unwrap();
+
+        assert_eq!(restored.get("app").unwrap().port, Some(5432));
+        assert!(serde_json::from_str::<State>(&content).is_ok());
+        assert!(corrupt.is_err());
+    }
 }